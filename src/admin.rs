@@ -0,0 +1,447 @@
+use tokio::net::UnixStream;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+use std::fs;
+use std::io::Read;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{SharedConn, DataBase, split_text};
+use crate::audit::AuditLog;
+use crate::ban::{self, Ban, BanList, BanTarget};
+use crate::config::{Config, Overrides};
+use crate::health::Health;
+use crate::listener;
+use crate::reload::reload;
+
+/// Handles to the server state needed to run admin commands
+pub struct AdminContext {
+    pub shared_conn: Arc<Mutex<SharedConn>>,
+    pub db: Arc<Mutex<DataBase>>,
+    pub bans: Arc<Mutex<BanList>>,
+    pub config: Arc<Mutex<Config>>,
+    pub config_path: String,
+    pub overrides: Overrides,
+    pub connections: Arc<AtomicUsize>, // Open connections
+    pub pending: Arc<AtomicUsize>,     // Connections in the log in phase
+    pub health: Arc<Health>,
+    pub audit: Arc<AuditLog>,
+    pub started: Instant,
+}
+
+/// Features enabled in the server running with `config`, reported by the `info` command
+pub fn capabilities(config: &Config) -> Vec<&'static str> {
+    let mut capabilities = Vec::new();
+    if config.keepalive_interval > 0 {
+        capabilities.push("heartbeat");
+    }
+    if !config.admins.is_empty() || !config.admin_socket.is_empty() {
+        capabilities.push("admin");
+    }
+    capabilities.push("bans");
+    if !config.motd_file.is_empty() {
+        capabilities.push("motd");
+    }
+    capabilities.push("info");
+    if config.all_listeners().iter().any(|listener| listener.websocket) {
+        capabilities.push("websocket");
+    }
+    if !config.federation.server_name.is_empty() {
+        capabilities.push("federation");
+    }
+    capabilities
+}
+
+/// Commands accepted by the admin interface
+#[derive(Debug, PartialEq)]
+pub enum AdminCommand {
+    Info,
+    Motd,
+    Sessions,
+    Groups,
+    Servers,
+    Kick(String, String), // Username, reason
+    Close(String, String), // Group name, reason
+    Ban(BanTarget, Option<u64>, String), // Target, duration in seconds, reason
+    Unban(BanTarget),
+    Bans,
+    Broadcast(String),
+    Reload,
+    Stats,
+    Help,
+}
+
+const HELP: &[&str] = &[
+    "info                     show server version, uptime and capabilities",
+    "motd                     show the message of the day",
+    "sessions                 list logged in users",
+    "groups                   list groups and their members",
+    "servers                  list the servers linked in the federation",
+    "kick <user> [reason]     close the session of a user",
+    "close <#group> [reason]  remove a group, notifying its members",
+    "ban <target> [duration] [reason]",
+    "                         ban a username, IP or CIDR (e.g. 10.0.0.0/8), for a duration",
+    "                         like 30m, 12h or 7d or permanently if not given",
+    "unban <target>           remove a ban",
+    "bans                     list active bans",
+    "broadcast <text>         send a message to every user",
+    "reload                   reload the configuration and the database",
+    "stats                    show server statistics",
+];
+
+impl AdminCommand {
+
+    /// Parses a command line as sent to the admin socket
+    pub fn parse(line: &str) -> Result<AdminCommand, String> {
+        let (name, rest) = split_first(line.trim());
+
+        match name {
+            "info" => Ok(AdminCommand::Info),
+            "motd" => Ok(AdminCommand::Motd),
+            "sessions" => Ok(AdminCommand::Sessions),
+            "groups" => Ok(AdminCommand::Groups),
+            "servers" => Ok(AdminCommand::Servers),
+            "kick" => {
+                let (user, reason) = split_first(rest);
+                if user.is_empty() {
+                    return Err("usage: kick <user> [reason]".to_string());
+                }
+                let reason = if reason.is_empty() { "Kicked by an admin" } else { reason };
+                Ok(AdminCommand::Kick(user.to_string(), reason.to_string()))
+            },
+            "close" => {
+                let (group, reason) = split_first(rest);
+                if !group.starts_with('#') {
+                    return Err("usage: close <#group> [reason]".to_string());
+                }
+                let reason = if reason.is_empty() { "Closed by an admin" } else { reason };
+                Ok(AdminCommand::Close(group.to_string(), reason.to_string()))
+            },
+            "ban" => {
+                let (target, rest) = split_first(rest);
+                let target = target.parse::<BanTarget>()
+                    .map_err(|e| format!("usage: ban <target> [duration] [reason]: {}", e))?;
+                // The duration is optional, if the next word is not a duration it's the reason
+                let (duration, reason) = match split_first(rest) {
+                    (d, r) if ban::parse_duration(d).is_some() => (ban::parse_duration(d), r),
+                    _ => (None, rest),
+                };
+                let reason = if reason.is_empty() { "Banned by an admin" } else { reason };
+                Ok(AdminCommand::Ban(target, duration, reason.to_string()))
+            },
+            "unban" => {
+                let target = rest.parse::<BanTarget>()
+                    .map_err(|e| format!("usage: unban <target>: {}", e))?;
+                Ok(AdminCommand::Unban(target))
+            },
+            "bans" => Ok(AdminCommand::Bans),
+            "broadcast" => {
+                if rest.is_empty() {
+                    return Err("usage: broadcast <text>".to_string());
+                }
+                Ok(AdminCommand::Broadcast(rest.to_string()))
+            },
+            "reload" => Ok(AdminCommand::Reload),
+            "stats" => Ok(AdminCommand::Stats),
+            "help" => Ok(AdminCommand::Help),
+            _ => Err(format!("unknown command '{}', try 'help'", name)),
+        }
+    }
+
+    /// Returns true if every user, not only admins, is allowed to run the command
+    pub fn is_public(&self) -> bool {
+        match self {
+            AdminCommand::Info | AdminCommand::Motd => true,
+            _ => false,
+        }
+    }
+}
+
+/// Splits the first word of a string from the rest
+fn split_first(s: &str) -> (&str, &str) {
+    match s.find(char::is_whitespace) {
+        Some(i) => (&s[..i], s[i..].trim()),
+        None => (s, ""),
+    }
+}
+
+impl AdminContext {
+
+    /// Returns true if the user is a server admin. Admins must be registered users, so that
+    /// their identity is checked with a password at log in.
+    pub async fn is_admin(&self, name: &str) -> bool {
+        self.config.lock().await.admins.iter().any(|a| a == name)
+            && self.db.lock().await.name_exists(name)
+    }
+
+    /// Returns the lines of the message of the day, if configured
+    pub async fn motd(&self) -> Vec<String> {
+        let path = self.config.lock().await.motd_file.clone();
+        if path.is_empty() {
+            return Vec::new();
+        }
+        let mut contents = String::new();
+        if let Err(err) = fs::File::open(&path).and_then(|mut f| f.read_to_string(&mut contents)) {
+            warn!("Cannot read the message of the day from {}: {}", path, err);
+            return Vec::new();
+        }
+        split_text(&contents)
+    }
+
+    /// Runs an admin command on behalf of `actor`, returning the lines of its output.
+    /// Commands that change the server state are recorded in the audit log.
+    pub async fn execute(&self, actor: &str, command: AdminCommand) -> Result<Vec<String>, String> {
+        match command {
+            AdminCommand::Info => {
+                let capabilities = capabilities(&*self.config.lock().await);
+                let shared_conn = self.shared_conn.lock().await;
+                Ok(vec![
+                    format!("version {}", env!("CARGO_PKG_VERSION")),
+                    format!("uptime {}", ban::format_duration(self.started.elapsed().as_secs())),
+                    format!("users {}", shared_conn.list_users().len()),
+                    format!("groups {}", shared_conn.list_groups().len()),
+                    format!("capabilities {}", capabilities.join(" ")),
+                ])
+            },
+
+            AdminCommand::Motd => Ok(self.motd().await),
+
+            AdminCommand::Sessions => {
+                let shared_conn = self.shared_conn.lock().await;
+                Ok(shared_conn.list_users().into_iter()
+                    .map(|name| match shared_conn.session_count(&name) {
+                        1 => name,
+                        count => format!("{} ({} sessions)", name, count),
+                    })
+                    .collect())
+            },
+
+            AdminCommand::Groups => Ok(self.shared_conn.lock().await.list_groups()
+                .into_iter()
+                .map(|(name, users)| format!("{}: {}", name, users.join(", ")))
+                .collect()),
+
+            AdminCommand::Servers => {
+                let shared_conn = self.shared_conn.lock().await;
+                let federation = shared_conn.federation();
+                if federation.name().is_empty() {
+                    return Err("Federation is disabled".to_string());
+                }
+                Ok(federation.servers().into_iter()
+                    .map(|(server, link)| if server == link {
+                        format!("{}: linked", server)
+                    } else {
+                        format!("{}: through {}", server, link)
+                    })
+                    .collect())
+            },
+
+            AdminCommand::Kick(user, reason) => {
+                self.shared_conn.lock().await.close(&user, &reason)
+                    .map_err(|err| err.to_string())?;
+                self.audit.record("kick", json::object!{
+                    "actor" => actor, "target" => user.clone(), "reason" => reason,
+                });
+                Ok(vec![format!("kicked {}", user)])
+            },
+
+            AdminCommand::Close(group, reason) => {
+                let members = self.shared_conn.lock().await.close_group(&group, &reason)
+                    .map_err(|err| err.to_string())?;
+                self.audit.record("close_group", json::object!{
+                    "actor" => actor, "group" => group.clone(), "members" => members,
+                    "reason" => reason,
+                });
+                Ok(vec![format!("closed {}", group)])
+            },
+
+            AdminCommand::Ban(target, duration, reason) => {
+                let created = ban::now();
+                let expires = duration.map(|d| created.saturating_add(d));
+                let entry = Ban { 
+                    target: target.clone(), 
+                    reason: reason.clone(),
+                    created,
+                    expires,
+                };
+                let description = entry.to_string();
+                self.bans.lock().await.add(entry)
+                    .map_err(|err| format!("cannot save ban: {}", err))?;
+                self.audit.record("ban", json::object!{
+                    "actor" => actor, "target" => target.to_string(), "expires" => expires,
+                    "reason" => reason.clone(),
+                });
+
+                // Disconnect the user, or the users of the address, if logged in
+                let notice = format!("Banned: {}", reason);
+                let mut output = vec![format!("banned {}", description)];
+                match &target {
+                    BanTarget::User(name) => {
+                        let _ = self.shared_conn.lock().await.close(name, &notice);
+                    },
+                    BanTarget::Ip(_) | BanTarget::Cidr(_, _) => {
+                        let closed = self.shared_conn.lock().await
+                            .close_addresses(|ip| target.matches_ip(ip), &notice);
+                        if closed > 0 {
+                            output.push(format!("closed {} sessions", closed));
+                        }
+                    },
+                }
+                Ok(output)
+            },
+
+            AdminCommand::Unban(target) => {
+                let removed = self.bans.lock().await.remove(&target)
+                    .map_err(|err| format!("cannot save bans: {}", err))?;
+                if !removed {
+                    return Err(format!("{} is not banned", target));
+                }
+                self.audit.record("unban", json::object!{
+                    "actor" => actor, "target" => target.to_string(),
+                });
+                Ok(vec![format!("unbanned {}", target)])
+            },
+
+            AdminCommand::Bans => Ok(self.bans.lock().await.list().iter()
+                .map(|b| b.to_string())
+                .collect()),
+
+            AdminCommand::Broadcast(text) => {
+                let count = self.shared_conn.lock().await.broadcast(&text);
+                self.audit.record("broadcast", json::object!{
+                    "actor" => actor, "users" => count, "text" => text,
+                });
+                Ok(vec![format!("sent to {} users", count)])
+            },
+
+            AdminCommand::Reload => {
+                let restart = reload(&self.config_path, &self.overrides, &self.config, &self.db)
+                    .await
+                    .map_err(|err| format!("reload failed: {}", err))?;
+                self.audit.record("reload", json::object!{ "actor" => actor });
+                Ok(restart.iter()
+                   .map(|s| format!("setting '{}' changed, restart required", s))
+                   .collect())
+            },
+
+            AdminCommand::Stats => {
+                let shared_conn = self.shared_conn.lock().await;
+                Ok(vec![
+                    format!("uptime_seconds {}", self.started.elapsed().as_secs()),
+                    format!("connections {}", self.connections.load(Ordering::SeqCst)),
+                    format!("pending_logins {}", self.pending.load(Ordering::SeqCst)),
+                    format!("users {}", shared_conn.list_users().len()),
+                    format!("groups {}", shared_conn.list_groups().len()),
+                ])
+            },
+
+            AdminCommand::Help => Ok(HELP.iter().map(|s| s.to_string()).collect()),
+        }
+    }
+}
+
+/// Listens for admin commands on a Unix domain socket at `path`. Access to the socket is
+/// restricted with the given file permission `mode`.
+///
+/// The protocol is line based: the client sends a command per line and the server answers with
+/// the output lines of the command followed by a line that is either `OK` or `ERR: <reason>`.
+pub async fn serve(path: &str, mode: u32, ctx: Arc<AdminContext>) -> Result<(), io::Error> {
+    let mut listener = listener::bind_unix(path, mode)?;
+    info!("Admin socket listening on {}", path);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let ctx = Arc::clone(&ctx);
+        tokio::spawn(async move {
+            if let Err(err) = handle(stream, ctx).await {
+                debug!("Admin connection error: {}", err);
+            }
+        });
+    }
+}
+
+async fn handle(mut stream: UnixStream, ctx: Arc<AdminContext>) -> Result<(), io::Error> {
+    let actor = socket_actor(&stream);
+    let (reader, mut writer) = stream.split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        trace!("Admin command: {}", line);
+
+        let result = match AdminCommand::parse(&line) {
+            Ok(command) => ctx.execute(&actor, command).await,
+            Err(err) => Err(err),
+        };
+
+        let mut response = String::new();
+        match result {
+            Ok(output) => {
+                for l in output {
+                    response.push_str(&l);
+                    response.push('\n');
+                }
+                response.push_str("OK\n");
+            },
+            Err(err) => response.push_str(&format!("ERR: {}\n", err)),
+        }
+        writer.write_all(response.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Identifies the local user connected to the admin socket, the actor of its commands
+fn socket_actor(stream: &UnixStream) -> String {
+    match stream.peer_cred() {
+        Ok(cred) => format!("admin socket (uid {})", cred.uid),
+        Err(err) => {
+            warn!("Cannot get the credentials of an admin socket client: {}", err);
+            "admin socket (unknown uid)".to_string()
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ListenerConfig;
+    use std::os::unix::fs::MetadataExt;
+
+    #[tokio::test]
+    async fn admin_socket_clients_are_identified_by_their_uid() {
+        let (client, _server) = UnixStream::pair().unwrap();
+        let path = std::env::temp_dir().join(format!("ostrich-uid-{}", std::process::id()));
+        fs::write(&path, "").unwrap();
+        let uid = fs::metadata(&path).unwrap().uid();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(socket_actor(&client), format!("admin socket (uid {})", uid));
+    }
+
+    #[test]
+    fn capabilities_follow_the_config() {
+        let mut config = Config { admin_socket: String::new(), ..Config::default() };
+        assert_eq!(capabilities(&config), vec!["heartbeat", "bans", "info"]);
+
+        config.admins = vec!["alice".to_string()];
+        config.motd_file = "motd.txt".to_string();
+        config.listeners.push(ListenerConfig {
+            address: "[::]:9998".to_string(),
+            websocket: true,
+            ..ListenerConfig::default()
+        });
+        config.federation.server_name = "a".to_string();
+        assert_eq!(capabilities(&config),
+                   vec!["heartbeat", "admin", "bans", "motd", "info", "websocket", "federation"]);
+
+        config.keepalive_interval = 0;
+        config.listeners.clear();
+        config.websocket_address = "127.0.0.1:9998".to_string();
+        assert!(!capabilities(&config).contains(&"heartbeat"));
+        assert!(capabilities(&config).contains(&"websocket"));
+    }
+}
@@ -0,0 +1,229 @@
+use chrono::{SecondsFormat, Utc};
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::config::ArchiveConfig;
+use crate::logfile::RotatingFile;
+use crate::logging::LogFormat;
+
+/// Max number of transcript files kept open, the least recently written is closed first
+const MAX_OPEN_FILES: usize = 64;
+
+/// Writes the transcripts of group conversations to disk. Each archived group has a file per
+/// format in the archive directory: `<group>.log` (plain text) and `<group>.jsonl` (JSON lines),
+/// rotated like the server log. The files are written by a thread of their own, so slow disks
+/// do not hold the callers.
+pub struct Archive {
+    config: ArchiveConfig,
+    writer: Option<Sender<Request>>,  // None when no group is archived or after shutdown
+    thread: Option<JoinHandle<()>>,   // Writer thread
+}
+
+/// Requests to the writer thread
+enum Request {
+    Write(String, LogFormat, String), // Group, format, line
+    Close(String),                    // Closes the transcripts of a group
+}
+
+impl Archive {
+
+    pub fn new(config: ArchiveConfig) -> Archive {
+        if !config.all_groups && config.groups.is_empty() {
+            return Archive { config, writer: None, thread: None };
+        }
+        let (tx, rx) = mpsc::channel();
+        let writer = Writer { config: config.clone(), files: HashMap::new(), writes: 0 };
+        let thread = thread::spawn(move || writer.run(rx));
+        Archive { config, writer: Some(tx), thread: Some(thread) }
+    }
+
+    /// An archive that does not record any group
+    pub fn disabled() -> Archive {
+        Archive::new(ArchiveConfig::default())
+    }
+
+    /// Returns true if the messages of the group are archived
+    pub fn is_archived(&self, group: &str) -> bool {
+        self.config.all_groups || self.config.groups.iter().any(|g| g == group)
+    }
+
+    /// Appends a message sent to a group to its transcripts
+    pub fn record(&self, group: &str, sender: &str, text: &str) {
+        if !self.is_archived(group) {
+            return;
+        }
+        let time = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+
+        for format in self.config.formats.clone() {
+            let line = match format {
+                // One line per message, line breaks inside the message are escaped
+                LogFormat::Text => format!("{} <{}> {}\n", time, sender, text.replace('\n', "\\n")),
+                LogFormat::Json => format!("{}\n", json::object!{
+                    "time" => time.as_str(),
+                    "group" => group,
+                    "sender" => sender,
+                    "text" => text,
+                }.dump()),
+            };
+            self.send(Request::Write(group.to_string(), format, line));
+        }
+    }
+
+    /// Closes the transcripts of a group that is gone
+    pub fn close(&self, group: &str) {
+        if self.is_archived(group) {
+            self.send(Request::Close(group.to_string()));
+        }
+    }
+
+    /// Waits for the writer to write the queued lines and close the files. Nothing is
+    /// archived afterwards.
+    pub fn shutdown(&mut self) {
+        // The writer stops once the queue is empty and its sender is gone
+        self.writer = None;
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("The archive writer stopped unexpectedly, transcripts may be incomplete");
+            }
+        }
+    }
+
+    fn send(&self, request: Request) {
+        if let Some(writer) = &self.writer {
+            if writer.send(request).is_err() {
+                warn!("Cannot archive messages, the archive writer stopped");
+            }
+        }
+    }
+}
+
+/// Owns the open transcript files
+struct Writer {
+    config: ArchiveConfig,
+    files: HashMap<(String, LogFormat), (RotatingFile, u64)>, // By group and format, last write
+    writes: u64,                                               // Writes so far
+}
+
+impl Writer {
+
+    fn run(mut self, requests: Receiver<Request>) {
+        for request in requests {
+            match request {
+                Request::Write(group, format, line) => {
+                    if let Err(err) = self.write(group.clone(), format, &line) {
+                        warn!("Cannot archive message to {}: {}", group, err);
+                    }
+                },
+                Request::Close(group) => self.files.retain(|(g, _), _| *g != group),
+            }
+        }
+    }
+
+    fn write(&mut self, group: String, format: LogFormat, line: &str) -> Result<(), io::Error> {
+        self.writes += 1;
+        let key = (group, format);
+        if !self.files.contains_key(&key) {
+            if self.files.len() >= MAX_OPEN_FILES {
+                let oldest = self.files.iter()
+                    .min_by_key(|(_, (_, last))| *last)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    self.files.remove(&oldest);
+                }
+            }
+            let file = self.open(&key.0, format)?;
+            self.files.insert(key.clone(), (file, 0));
+        }
+        match self.files.get_mut(&key) {
+            Some((file, last)) => {
+                *last = self.writes;
+                file.write_all(line.as_bytes())
+            },
+            None => Ok(()),
+        }
+    }
+
+    fn open(&self, group: &str, format: LogFormat) -> Result<RotatingFile, io::Error> {
+        fs::create_dir_all(&self.config.directory)?;
+
+        let extension = match format {
+            LogFormat::Text => "log",
+            LogFormat::Json => "jsonl",
+        };
+        let name = format!("{}.{}", file_name(group), extension);
+        let path = Path::new(&self.config.directory).join(name);
+
+        RotatingFile::open(path,
+                           self.config.max_size,
+                           Duration::from_secs(self.config.rotate_interval),
+                           self.config.retention)
+    }
+}
+
+/// Name of the transcripts of a group, without the extension. The `#` of the group is dropped,
+/// letters, digits and `-` are kept, and any other byte is written as `_` and its hex value, so
+/// every group has its own name.
+fn file_name(group: &str) -> String {
+    let group = group.strip_prefix('#').unwrap_or(group);
+    if group.is_empty() {
+        return "_".to_string();
+    }
+    let mut name = String::new();
+    for c in group.chars() {
+        if c.is_alphanumeric() || c == '-' {
+            name.push(c);
+        } else {
+            let mut bytes = [0; 4];
+            for byte in c.encode_utf8(&mut bytes).bytes() {
+                name.push_str(&format!("_{:02x}", byte));
+            }
+        }
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_group_has_its_own_file_name() {
+        assert_eq!(file_name("#support"), "support");
+        assert_eq!(file_name("#a.b"), "a_2eb");
+        assert_eq!(file_name("#a_b"), "a_5fb");
+        assert_eq!(file_name("#a_2eb"), "a_5f2eb");
+        assert_eq!(file_name("##a"), "_23a");
+        assert_eq!(file_name("#"), "_");
+        assert_eq!(file_name("#../x"), "_2e_2e_2fx");
+    }
+
+    #[test]
+    fn queued_lines_are_written_on_shutdown() {
+        let directory = std::env::temp_dir().join(format!("ostrich-archive-{}", std::process::id()));
+        let mut archive = Archive::new(ArchiveConfig {
+            all_groups: true,
+            directory: directory.to_string_lossy().into_owned(),
+            formats: vec![LogFormat::Text, LogFormat::Json],
+            ..ArchiveConfig::default()
+        });
+        for i in 0..100 {
+            archive.record("#g", "alice", &format!("hi {}", i));
+        }
+        archive.shutdown();
+        archive.record("#g", "alice", "too late");
+
+        let text = fs::read_to_string(directory.join("g.log")).unwrap();
+        let json = fs::read_to_string(directory.join("g.jsonl")).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(text.lines().count(), 100);
+        assert!(text.lines().last().unwrap().ends_with(" <alice> hi 99"));
+        assert_eq!(json.lines().count(), 100);
+        assert_eq!(json::parse(json.lines().last().unwrap()).unwrap()["text"], "hi 99");
+    }
+}
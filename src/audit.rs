@@ -0,0 +1,146 @@
+use chrono::{SecondsFormat, Utc};
+use json::JsonValue;
+use sha2::{Digest, Sha256};
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::Mutex;
+
+use crate::logging::Session;
+
+/// Append-only log of security relevant events (log ins, bans, admin actions...), written as one
+/// JSON object per line.
+///
+/// With the hash chain enabled every entry carries the `hash` of the previous entry in `prev` and
+/// its own `hash`: the hex SHA-256 of the entry serialized without the `hash` field. Removing or
+/// editing an entry breaks the chain from that point on.
+pub struct AuditLog {
+    writer: Mutex<Option<Writer>>, // None when the audit log is disabled
+}
+
+struct Writer {
+    path: String,
+    file: File,
+    chain: bool,
+    last_hash: String,
+}
+
+impl AuditLog {
+
+    /// Opens the audit log at `path` for appending
+    pub fn open(path: &str, chain: bool) -> Result<AuditLog, io::Error> {
+        // Continue the chain of the existing entries
+        let last_hash = match File::open(path) {
+            Ok(file) => BufReader::new(file).lines()
+                .filter_map(|l| l.ok())
+                .filter(|l| !l.trim().is_empty())
+                .last()
+                .and_then(|l| json::parse(&l).ok())
+                .and_then(|e| e["hash"].as_str().map(|h| h.to_string()))
+                .unwrap_or_default(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let writer = Writer { path: path.to_string(), file, chain, last_hash };
+        Ok(AuditLog { writer: Mutex::new(Some(writer)) })
+    }
+
+    /// An audit log that discards every event
+    pub fn disabled() -> AuditLog {
+        AuditLog { writer: Mutex::new(None) }
+    }
+
+    /// Records an event. The entry has the time, the event name, the session and source address
+    /// of the running connection (if any) and the given fields.
+    pub fn record(&self, event: &str, fields: JsonValue) {
+        let mut writer = match self.writer.lock() {
+            Ok(w) => w,
+            Err(_) => return,
+        };
+        let writer = match writer.as_mut() {
+            Some(w) => w,
+            None => return,
+        };
+
+        let mut entry = json::object!{
+            "time" => Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            "event" => event,
+        };
+        if let Some(session) = Session::current() {
+            entry["session"] = session.id.into();
+            entry["source"] = session.peer.clone().into();
+            if let Some(user) = session.user() {
+                entry["user"] = user.into();
+            }
+        }
+        for (key, value) in fields.entries() {
+            entry[key] = value.clone();
+        }
+
+        if writer.chain {
+            entry["prev"] = writer.last_hash.clone().into();
+            let hash = format!("{:x}", Sha256::digest(entry.dump().as_bytes()));
+            entry["hash"] = hash.clone().into();
+            writer.last_hash = hash;
+        }
+
+        let line = format!("{}\n", entry.dump());
+        if let Err(err) = writer.file.write_all(line.as_bytes()) {
+            error!("Cannot write to the audit log {}: {}", writer.path, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks the hash chain of the entries, returning the index of the first broken one
+    fn broken_link(entries: &[JsonValue]) -> Option<usize> {
+        let mut prev = String::new();
+        for (i, entry) in entries.iter().enumerate() {
+            let mut unhashed = entry.clone();
+            let hash = unhashed.remove("hash");
+            let expected = format!("{:x}", Sha256::digest(unhashed.dump().as_bytes()));
+            if entry["prev"] != prev.as_str() || hash != expected.as_str() {
+                return Some(i);
+            }
+            prev = expected;
+        }
+        None
+    }
+
+    fn entries(path: &str) -> Vec<JsonValue> {
+        std::fs::read_to_string(path).unwrap().lines()
+            .map(|line| json::parse(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn entries_are_chained_across_restarts() {
+        let path = std::env::temp_dir().join(format!("ostrich-audit-{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let audit = AuditLog::open(path, true).unwrap();
+        audit.record("ban", json::object!{ "actor" => "admin socket (uid 0)", "target" => "mallory" });
+        audit.record("reload", json::object!{ "actor" => "alice" });
+        drop(audit);
+        AuditLog::open(path, true).unwrap().record("unban", json::object!{ "target" => "mallory" });
+
+        let mut log = entries(path);
+        assert_eq!(log.len(), 3);
+        assert_eq!(broken_link(&log), None);
+        // Entries written outside of a session still name who acted
+        assert_eq!(log[0]["actor"], "admin socket (uid 0)");
+        assert!(log[0]["session"].is_null());
+
+        log[1]["actor"] = "bob".into();
+        assert_eq!(broken_link(&log), Some(1));
+        log.remove(1);
+        assert_eq!(broken_link(&log), Some(1));
+        std::fs::remove_file(path).unwrap();
+    }
+}
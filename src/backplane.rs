@@ -0,0 +1,1021 @@
+use ostrich_core::{Command, ListUsrOperation};
+
+use json::JsonValue;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::{Mutex, mpsc};
+use tokio::time::{self, Duration};
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{self, Arc};
+use std::time::Instant;
+
+use crate::SharedConn;
+use crate::config::BackplaneConfig;
+
+/// Seconds to wait before connecting again to Redis after the connection is lost
+const RECONNECT_INTERVAL: u64 = 5;
+/// Heartbeats an instance may miss before it is considered gone
+const HEARTBEAT_MISSES: u32 = 3;
+/// Max length of a Redis reply line or bulk string
+const MAX_REPLY: usize = 1024 * 1024;
+
+/// Change of state of an instance, shared with the other instances of the cluster
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// The user has `sessions` sessions on the instance, published on every change
+    Online { instance: String, user: String, sessions: usize },
+    Offline { instance: String, user: String },
+    Join { instance: String, group: String, user: String },
+    Leave { instance: String, group: String, user: String },
+    Direct { instance: String, sender: String, target: String, text: String },
+    Group { instance: String, group: String, sender: String, text: String },
+    /// The instance (re)joined the cluster, the others forget what they knew about it and
+    /// announce their own users and groups again
+    Sync { instance: String },
+    /// Published periodically, instances that go silent are forgotten
+    Heartbeat { instance: String },
+}
+
+impl Event {
+
+    /// Instance that published the event
+    pub fn instance(&self) -> &str {
+        match self {
+            Event::Online { instance, .. } | Event::Offline { instance, .. } |
+            Event::Join { instance, .. } | Event::Leave { instance, .. } |
+            Event::Direct { instance, .. } | Event::Group { instance, .. } |
+            Event::Sync { instance } | Event::Heartbeat { instance } => instance,
+        }
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        match self {
+            Event::Online { instance, user, sessions } => json::object!{
+                "type" => "online", "instance" => instance.as_str(), "user" => user.as_str(),
+                "sessions" => *sessions,
+            },
+            Event::Offline { instance, user } => json::object!{
+                "type" => "offline", "instance" => instance.as_str(), "user" => user.as_str(),
+            },
+            Event::Join { instance, group, user } => json::object!{
+                "type" => "join", "instance" => instance.as_str(),
+                "group" => group.as_str(), "user" => user.as_str(),
+            },
+            Event::Leave { instance, group, user } => json::object!{
+                "type" => "leave", "instance" => instance.as_str(),
+                "group" => group.as_str(), "user" => user.as_str(),
+            },
+            Event::Direct { instance, sender, target, text } => json::object!{
+                "type" => "direct", "instance" => instance.as_str(),
+                "sender" => sender.as_str(), "target" => target.as_str(), "text" => text.as_str(),
+            },
+            Event::Group { instance, group, sender, text } => json::object!{
+                "type" => "group", "instance" => instance.as_str(),
+                "group" => group.as_str(), "sender" => sender.as_str(), "text" => text.as_str(),
+            },
+            Event::Sync { instance } => json::object!{
+                "type" => "sync", "instance" => instance.as_str(),
+            },
+            Event::Heartbeat { instance } => json::object!{
+                "type" => "heartbeat", "instance" => instance.as_str(),
+            },
+        }
+    }
+
+    pub fn from_json(value: &JsonValue) -> Result<Event, io::Error> {
+        let field = |name: &str| -> Result<String, io::Error> {
+            value[name].as_str().map(|s| s.to_string()).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("missing field '{}'", name))
+            })
+        };
+        let instance = field("instance")?;
+
+        match value["type"].as_str().unwrap_or("") {
+            "online" => Ok(Event::Online {
+                instance, user: field("user")?, sessions: value["sessions"].as_usize().unwrap_or(1),
+            }),
+            "offline" => Ok(Event::Offline { instance, user: field("user")? }),
+            "join" => Ok(Event::Join { instance, group: field("group")?, user: field("user")? }),
+            "leave" => Ok(Event::Leave { instance, group: field("group")?, user: field("user")? }),
+            "direct" => Ok(Event::Direct {
+                instance, sender: field("sender")?, target: field("target")?, text: field("text")?,
+            }),
+            "group" => Ok(Event::Group {
+                instance, group: field("group")?, sender: field("sender")?, text: field("text")?,
+            }),
+            "sync" => Ok(Event::Sync { instance }),
+            "heartbeat" => Ok(Event::Heartbeat { instance }),
+            other => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                        format!("unknown event type '{}'", other))),
+        }
+    }
+}
+
+/// Transport sharing events between the instances of a cluster. Calls never wait for the
+/// transport, events are queued and delivered in the background.
+pub trait Backplane: Send + Sync {
+
+    /// Name of this instance, unique in the cluster
+    fn instance(&self) -> &str;
+
+    /// Sends an event to every other instance
+    fn publish(&self, event: Event);
+
+    /// Takes the receiver of the events of the other instances. The first event is a `Sync`
+    /// of this instance, whenever it (re)joins the cluster, the other instances answer it
+    /// with `Online` events for their users. Returns None after the first call.
+    fn subscribe(&self) -> Option<mpsc::UnboundedReceiver<Event>>;
+}
+
+/// Users and groups of the other instances, as known from the backplane events: every instance
+/// keeps this presence registry of the others. A user may be logged in to several instances,
+/// and be a member of a group from several of them.
+pub struct Cluster {
+    backplane: Option<Arc<dyn Backplane>>,
+    presence: HashMap<String, Vec<(String, usize)>>, // Users on other instances, their instances and sessions
+    members: HashMap<String, Vec<(String, String)>>, // Group name, members on other instances and their instance
+    last_seen: HashMap<String, Instant>,             // Instance, last time an event came from it
+    expired: Vec<String>,                            // Instances forgotten for being silent
+}
+
+impl Cluster {
+
+    pub fn new(backplane: Arc<dyn Backplane>) -> Cluster {
+        Cluster {
+            backplane: Some(backplane),
+            presence: HashMap::new(),
+            members: HashMap::new(),
+            last_seen: HashMap::new(),
+            expired: Vec::new(),
+        }
+    }
+
+    /// A server running as a single instance
+    pub fn disabled() -> Cluster {
+        Cluster {
+            backplane: None,
+            presence: HashMap::new(),
+            members: HashMap::new(),
+            last_seen: HashMap::new(),
+            expired: Vec::new(),
+        }
+    }
+
+    /// Returns true if a user is logged in to another instance
+    pub fn is_online(&self, user: &str) -> bool {
+        self.presence.contains_key(user)
+    }
+
+    /// Number of sessions a user has on the other instances
+    pub fn remote_sessions(&self, user: &str) -> usize {
+        self.presence.get(user).into_iter().flatten().map(|(_, sessions)| sessions).sum()
+    }
+
+    /// Members of a group on other instances, each listed once
+    pub fn remote_members(&self, group: &str) -> Vec<String> {
+        let mut users: Vec<String> = Vec::new();
+        for (user, _) in self.members.get(group).into_iter().flatten() {
+            if !users.contains(user) {
+                users.push(user.clone());
+            }
+        }
+        users
+    }
+
+    /// Users logged in to other instances, sorted
+    pub fn remote_users(&self) -> Vec<String> {
+        let mut users: Vec<String> = self.presence.keys().cloned().collect();
+        users.sort();
+        users
+    }
+
+    /// Records the number of sessions a user has on this instance, for the other instances to
+    /// enforce the limit of sessions per user across the cluster
+    pub fn set_sessions(&self, user: &str, sessions: usize) {
+        match sessions {
+            0 => self.publish(|instance| Event::Offline { instance, user: user.to_string() }),
+            _ => self.publish(|instance| Event::Online { instance, user: user.to_string(), sessions }),
+        }
+    }
+
+    pub fn joined(&self, group: &str, user: &str) {
+        self.publish(|instance| Event::Join { instance, group: group.to_string(), user: user.to_string() });
+    }
+
+    pub fn left(&self, group: &str, user: &str) {
+        self.publish(|instance| Event::Leave { instance, group: group.to_string(), user: user.to_string() });
+    }
+
+    /// Relays a message to a user of another instance
+    pub fn send_direct(&self, sender: &str, target: &str, text: &str) {
+        self.publish(|instance| Event::Direct {
+            instance, sender: sender.to_string(), target: target.to_string(), text: text.to_string(),
+        });
+    }
+
+    /// Relays a message sent to a group to the members on other instances, and to the
+    /// sessions the local `members` have there
+    pub fn group_message(&self, group: &str, members: &[String], sender: &str, text: &str) {
+        if !self.members.contains_key(group) && !members.iter().any(|user| self.is_online(user)) {
+            return;
+        }
+        self.publish(|instance| Event::Group {
+            instance, group: group.to_string(), sender: sender.to_string(), text: text.to_string(),
+        });
+    }
+
+    fn publish<F: FnOnce(String) -> Event>(&self, event: F) {
+        if let Some(backplane) = &self.backplane {
+            backplane.publish(event(backplane.instance().to_string()));
+        }
+    }
+
+    /// Returns the instances not heard from in `timeout`, they are considered gone
+    fn expire(&mut self, timeout: Duration) -> Vec<String> {
+        let silent: Vec<String> = self.last_seen.iter()
+            .filter(|(_, seen)| seen.elapsed() >= timeout)
+            .map(|(instance, _)| instance.clone())
+            .collect();
+        for instance in &silent {
+            self.last_seen.remove(instance);
+            self.expired.push(instance.clone());
+        }
+        silent
+    }
+
+    /// Forgets the users of an instance, or of every other instance if None. Returns the
+    /// groups left by users that are no longer members from any instance.
+    fn forget(&mut self, instance: Option<&str>) -> Vec<(String, String)> {
+        let forgotten = |i: &str| match instance {
+            Some(instance) => i == instance,
+            None => true,
+        };
+        for instances in self.presence.values_mut() {
+            instances.retain(|(i, _)| !forgotten(i));
+        }
+        self.presence.retain(|_, instances| !instances.is_empty());
+
+        let mut gone = Vec::new();
+        for (group, members) in self.members.iter_mut() {
+            let mut removed = Vec::new();
+            members.retain(|(user, i)| if forgotten(i) {
+                removed.push(user.clone());
+                false
+            } else {
+                true
+            });
+            for user in removed {
+                let entry = (group.clone(), user);
+                if !members.iter().any(|(u, _)| *u == entry.1) && !gone.contains(&entry) {
+                    gone.push(entry);
+                }
+            }
+        }
+        self.members.retain(|_, members| !members.is_empty());
+        gone
+    }
+}
+
+impl SharedConn {
+
+    /// Applies an event of another instance
+    fn cluster_event(&mut self, event: Event) {
+        let own = match &self.cluster.backplane {
+            Some(backplane) => event.instance() == backplane.instance(),
+            None => return,
+        };
+
+        if !own {
+            let instance = event.instance().to_string();
+            // An instance forgotten for being silent is back without having resynced (e.g. it
+            // was stalled): resync everyone, to learn its users again
+            let back = match self.cluster.expired.iter().position(|i| *i == instance) {
+                Some(index) => {
+                    self.cluster.expired.remove(index);
+                    true
+                },
+                None => false,
+            };
+            self.cluster.last_seen.insert(instance, Instant::now());
+            if back && !matches!(event, Event::Sync { .. }) {
+                if let Some(backplane) = &self.cluster.backplane {
+                    let instance = backplane.instance().to_string();
+                    self.cluster_event(Event::Sync { instance });
+                }
+            }
+        }
+
+        match event {
+            // This instance (re)joined: start over and ask the others for their state. They
+            // forget what they knew about this instance, so announce its users again.
+            Event::Sync { instance } if own => {
+                for (group, user) in self.cluster.forget(None) {
+                    self.notify_remote_member(&group, ListUsrOperation::Remove, &user);
+                }
+                self.leave_gone_users();
+                self.cluster.publish(|_| Event::Sync { instance });
+                self.announce();
+            },
+            // Another instance (re)joined: it lost its users, tell it about ours
+            Event::Sync { instance } => {
+                for (group, user) in self.cluster.forget(Some(&instance)) {
+                    self.notify_remote_member(&group, ListUsrOperation::Remove, &user);
+                }
+                self.leave_gone_users();
+                self.announce();
+            },
+            _ if own => (),
+
+            Event::Heartbeat { .. } => (),
+
+            Event::Online { instance, user, sessions } => {
+                let instances = self.cluster.presence.entry(user).or_default();
+                match instances.iter_mut().find(|(i, _)| *i == instance) {
+                    Some(entry) => entry.1 = sessions,
+                    None => instances.push((instance, sessions)),
+                }
+            },
+            Event::Offline { instance, user } => {
+                if let Some(instances) = self.cluster.presence.get_mut(&user) {
+                    instances.retain(|(i, _)| *i != instance);
+                    if instances.is_empty() {
+                        self.cluster.presence.remove(&user);
+                    }
+                }
+                // Its memberships from this instance were kept for its sessions there
+                self.leave_gone_users();
+            },
+
+            Event::Join { instance, group, user } => {
+                let members = self.cluster.members.entry(group.clone()).or_default();
+                let known = members.iter().any(|(u, _)| *u == user);
+                let entry = (user, instance);
+                if !members.contains(&entry) {
+                    members.push(entry.clone());
+                    if !known {
+                        self.notify_remote_member(&group, ListUsrOperation::Add, &entry.0);
+                    }
+                }
+            },
+            Event::Leave { instance, group, user } => {
+                if let Some(members) = self.cluster.members.get_mut(&group) {
+                    if let Some(index) = members.iter().position(|(u, i)| *u == user && *i == instance) {
+                        members.remove(index);
+                        if !members.iter().any(|(u, _)| *u == user) {
+                            self.notify_remote_member(&group, ListUsrOperation::Remove, &user);
+                        }
+                    }
+                }
+                self.cluster.members.retain(|_, members| !members.is_empty());
+            },
+
+            Event::Direct { sender, target, text, .. } => {
+                let command = Command::Msg(sender, target.clone(), text);
+                self.deliver(&target, command);
+            },
+            Event::Group { group, sender, text, .. } => {
+                let command = Command::Msg(sender.clone(), group.clone(), text);
+                self.deliver_to_group(&group, &command, &sender);
+            },
+        }
+    }
+
+    /// Publishes the users of this instance and the groups they are members of
+    fn announce(&self) {
+        for (user, sessions) in &self.shared_conn {
+            self.cluster.set_sessions(user, sessions.len());
+        }
+        for (group, users) in &self.groups {
+            for user in users {
+                self.cluster.joined(group, user);
+            }
+        }
+    }
+
+    /// Notifies the members of a group that a user of another instance joined or left it,
+    /// unless the user is also a member from this instance
+    fn notify_remote_member(&self, group: &str, operation: ListUsrOperation, user: &str) {
+        let local = match self.groups.get(group) {
+            Some(users) => users.iter().any(|u| u == user),
+            None => false,
+        };
+        if !local {
+            self.notify_group(group, operation, user);
+        }
+    }
+
+    /// Publishes the heartbeat of this instance and forgets the instances that went silent
+    fn cluster_heartbeat(&mut self, timeout: Duration) {
+        self.cluster.publish(|instance| Event::Heartbeat { instance });
+        for instance in self.cluster.expire(timeout) {
+            warn!("Instance {} went silent, forgetting its users", instance);
+            for (group, user) in self.cluster.forget(Some(&instance)) {
+                self.notify_remote_member(&group, ListUsrOperation::Remove, &user);
+            }
+            self.leave_gone_users();
+        }
+    }
+}
+
+/// Applies the events of the other instances to the server state, and publishes the heartbeat
+/// of this instance every `heartbeat_interval`. Instances that miss `HEARTBEAT_MISSES`
+/// heartbeats in a row are forgotten.
+pub fn start(backplane: &dyn Backplane, shared_conn: Arc<Mutex<SharedConn>>, heartbeat_interval: Duration) {
+    let mut events = match backplane.subscribe() {
+        Some(events) => events,
+        None => return,
+    };
+    let events_conn = Arc::clone(&shared_conn);
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            events_conn.lock().await.cluster_event(event);
+        }
+    });
+    tokio::spawn(async move {
+        let mut interval = time::interval(heartbeat_interval);
+        loop {
+            interval.tick().await;
+            shared_conn.lock().await.cluster_heartbeat(heartbeat_interval * HEARTBEAT_MISSES);
+        }
+    });
+}
+
+/// Backplane of the instances running in the same process, used to test clusters without
+/// a Redis server. Every instance gets its backplane from the same hub.
+#[derive(Clone, Default)]
+pub struct MemoryHub(Arc<sync::Mutex<HubState>>);
+
+#[derive(Default)]
+struct HubState {
+    subscribers: Vec<(String, mpsc::UnboundedSender<Event>)>, // Instance, events to it
+}
+
+impl MemoryHub {
+
+    pub fn new() -> MemoryHub {
+        MemoryHub::default()
+    }
+
+    /// Joins an instance to the cluster
+    pub fn backplane(&self, instance: &str) -> MemoryBackplane {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut state = self.0.lock().unwrap();
+
+        let _ = tx.send(Event::Sync { instance: instance.to_string() });
+        state.subscribers.push((instance.to_string(), tx));
+
+        MemoryBackplane {
+            instance: instance.to_string(),
+            hub: self.clone(),
+            events: sync::Mutex::new(Some(rx)),
+        }
+    }
+}
+
+pub struct MemoryBackplane {
+    instance: String,
+    hub: MemoryHub,
+    events: sync::Mutex<Option<mpsc::UnboundedReceiver<Event>>>,
+}
+
+impl Backplane for MemoryBackplane {
+
+    fn instance(&self) -> &str {
+        &self.instance
+    }
+
+    fn publish(&self, event: Event) {
+        let mut state = self.hub.0.lock().unwrap();
+        // Instances that are gone are removed when found
+        state.subscribers.retain(|(instance, tx)| {
+            *instance == self.instance || tx.send(event.clone()).is_ok()
+        });
+    }
+
+    fn subscribe(&self) -> Option<mpsc::UnboundedReceiver<Event>> {
+        self.events.lock().unwrap().take()
+    }
+}
+
+/// Backplane on a Redis server (or any server speaking its protocol). Events are published
+/// to the `<prefix>events` channel. Events published while the connection to Redis is down are
+/// lost, the instance syncs again with the others when it reconnects.
+pub struct RedisBackplane {
+    instance: String,
+    channel: String,
+    requests: mpsc::UnboundedSender<Vec<String>>, // Commands to send to Redis
+    events: sync::Mutex<Option<mpsc::UnboundedReceiver<Event>>>,
+}
+
+impl RedisBackplane {
+
+    /// Starts connecting to the Redis server, in the background
+    pub fn connect(config: &BackplaneConfig) -> RedisBackplane {
+        let channel = format!("{}events", config.prefix);
+        let (requests, requests_rx) = mpsc::unbounded_channel();
+        let (events_tx, events) = mpsc::unbounded_channel();
+
+        tokio::spawn(run_commands(config.clone(), requests_rx));
+        tokio::spawn(run_subscriber(config.clone(), channel.clone(), events_tx));
+
+        RedisBackplane {
+            instance: config.instance.clone(),
+            channel,
+            requests,
+            events: sync::Mutex::new(Some(events)),
+        }
+    }
+
+    fn request(&self, args: &[&str]) {
+        let _ = self.requests.send(args.iter().map(|a| a.to_string()).collect());
+    }
+}
+
+impl Backplane for RedisBackplane {
+
+    fn instance(&self) -> &str {
+        &self.instance
+    }
+
+    fn publish(&self, event: Event) {
+        self.request(&["PUBLISH", &self.channel, &event.to_json().dump()]);
+    }
+
+    fn subscribe(&self) -> Option<mpsc::UnboundedReceiver<Event>> {
+        self.events.lock().unwrap().take()
+    }
+}
+
+/// Value of a Redis reply. Nested arrays are not used by the commands sent.
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Reply::Status(s) | Reply::Bulk(Some(s)) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+async fn redis_connect(config: &BackplaneConfig)
+    -> Result<(BufReader<tokio::io::ReadHalf<TcpStream>>, tokio::io::WriteHalf<TcpStream>), io::Error> {
+    let stream = TcpStream::connect(&config.redis_address).await?;
+    let (reader, writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let mut writer = writer;
+    if !config.redis_password.is_empty() {
+        command(&mut reader, &mut writer, &["AUTH", &config.redis_password]).await?;
+    }
+    Ok((reader, writer))
+}
+
+/// Sends a command and reads its reply
+async fn command<R, W>(reader: &mut R, writer: &mut W, args: &[&str]) -> Result<Reply, io::Error>
+    where R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin {
+    write_command(writer, args).await?;
+    read_reply(reader).await
+}
+
+/// Writes a command as an array of bulk strings
+async fn write_command<W: AsyncWrite + Unpin>(writer: &mut W, args: &[&str]) -> Result<(), io::Error> {
+    let mut buf = format!("*{}\r\n", args.len());
+    for arg in args {
+        buf.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+    writer.write_all(buf.as_bytes()).await
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<String, io::Error> {
+    let mut line = Vec::new();
+    if (&mut *reader).take(MAX_REPLY as u64).read_until(b'\n', &mut line).await? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Redis closed the connection"));
+    }
+    if !line.ends_with(b"\r\n") {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated Redis reply"));
+    }
+    line.truncate(line.len() - 2);
+    String::from_utf8(line).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Redis reply is not UTF-8"))
+}
+
+/// Reads a reply, error replies are returned as errors
+async fn read_reply<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Reply, io::Error> {
+    let reply = read_scalar(reader).await?;
+    if let Reply::Array(ref items) = reply {
+        // The array holds the length, read its items
+        let len = items.len();
+        let mut items = Vec::with_capacity(len);
+        for _ in 0..len {
+            items.push(read_scalar(reader).await?);
+        }
+        return Ok(Reply::Array(items));
+    }
+    Ok(reply)
+}
+
+/// Reads a reply that is not an array, for arrays returns one with placeholders for its items
+async fn read_scalar<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Reply, io::Error> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("invalid Redis reply: {}", msg));
+    let line = read_line(reader).await?;
+    if line.is_empty() {
+        return Err(invalid("empty line"));
+    }
+    let (kind, rest) = line.split_at(1);
+    let number = || rest.parse::<i64>().map_err(|_| invalid("bad length"));
+
+    match kind {
+        "+" => Ok(Reply::Status(rest.to_string())),
+        "-" => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Redis error: {}", rest))),
+        ":" => Ok(Reply::Integer(number()?)),
+        "$" => {
+            let len = number()?;
+            if len < 0 {
+                return Ok(Reply::Bulk(None));
+            }
+            if len as usize > MAX_REPLY {
+                return Err(invalid("bulk string too long"));
+            }
+            let mut data = vec![0u8; len as usize + 2];
+            reader.read_exact(&mut data).await?;
+            data.truncate(len as usize);
+            String::from_utf8(data).map(|s| Reply::Bulk(Some(s))).map_err(|_| invalid("not UTF-8"))
+        },
+        "*" => {
+            let len = number()?;
+            if len as usize > MAX_REPLY {
+                return Err(invalid("array too long"));
+            }
+            Ok(Reply::Array((0..len.max(0)).map(|_| Reply::Bulk(None)).collect()))
+        },
+        _ => Err(invalid("unknown type")),
+    }
+}
+
+/// Sends the queued commands to Redis, reconnecting when the connection is lost
+async fn run_commands(config: BackplaneConfig,
+                      mut requests: mpsc::UnboundedReceiver<Vec<String>>) {
+    let mut pending: Option<Vec<String>> = None; // Command sent when the connection was lost
+    loop {
+        let result: Result<(), io::Error> = async {
+            let (mut reader, mut writer) = redis_connect(&config).await?;
+            info!("Connected to the Redis backplane at {}", config.redis_address);
+
+            loop {
+                let owned = match pending.take() {
+                    Some(args) => args,
+                    None => match requests.recv().await {
+                        Some(args) => args,
+                        None => break,
+                    },
+                };
+                let args: Vec<&str> = owned.iter().map(|a| a.as_str()).collect();
+                if let Err(err) = command(&mut reader, &mut writer, &args).await {
+                    if err.kind() != io::ErrorKind::InvalidInput {
+                        // Send it again once reconnected, it may be the Sync of this instance
+                        pending = Some(owned);
+                        return Err(err);
+                    }
+                    warn!("Redis backplane {} failed: {}", args[0], err);
+                }
+            }
+            Ok(())
+        }.await;
+
+        match result {
+            Ok(()) => return, // The backplane was dropped
+            Err(err) => warn!("Redis backplane connection lost: {}", err),
+        }
+        time::delay_for(Duration::from_secs(RECONNECT_INTERVAL)).await;
+    }
+}
+
+/// Receives the events of the other instances, reconnecting when the connection is lost
+async fn run_subscriber(config: BackplaneConfig,
+                        channel: String,
+                        events: mpsc::UnboundedSender<Event>) {
+    loop {
+        let result: Result<(), io::Error> = async {
+            let (mut reader, mut writer) = redis_connect(&config).await?;
+            command(&mut reader, &mut writer, &["SUBSCRIBE", &channel]).await?;
+
+            // Events may have been missed while disconnected, sync with the other instances
+            if events.send(Event::Sync { instance: config.instance.clone() }).is_err() {
+                return Ok(());
+            }
+            loop {
+                let message = match read_reply(&mut reader).await? {
+                    Reply::Array(items) => items,
+                    _ => continue,
+                };
+                let payload = match (message.first().and_then(|r| r.as_str()), message.get(2)) {
+                    (Some("message"), Some(Reply::Bulk(Some(payload)))) => payload.clone(),
+                    _ => continue,
+                };
+                let event = match json::parse(&payload).map_err(|e| e.to_string())
+                    .and_then(|v| Event::from_json(&v).map_err(|e| e.to_string())) {
+                    Ok(event) => event,
+                    Err(err) => {
+                        warn!("Ignoring invalid backplane event: {}", err);
+                        continue;
+                    },
+                };
+                if event.instance() != config.instance && events.send(event).is_err() {
+                    return Ok(());
+                }
+            }
+        }.await;
+
+        match result {
+            Ok(()) => return,
+            Err(err) => warn!("Redis backplane subscription lost: {}", err),
+        }
+        time::delay_for(Duration::from_secs(RECONNECT_INTERVAL)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Outgoing, Rx, SessionId};
+    use crate::archive::Archive;
+    use crate::federation::Federation;
+
+    /// An instance of a cluster on a `MemoryHub`, its events are applied by `settle` in place
+    /// of the task spawned by `start`
+    struct Node {
+        conn: SharedConn,
+        events: mpsc::UnboundedReceiver<Event>,
+    }
+
+    impl Node {
+        fn new(hub: &MemoryHub, name: &str) -> Node {
+            let backplane = Arc::new(hub.backplane(name));
+            let events = backplane.subscribe().unwrap();
+            let conn = SharedConn::new(Archive::disabled(), Federation::disabled(), Cluster::new(backplane));
+            Node { conn, events }
+        }
+
+        fn login(&mut self, name: &str, session: SessionId) -> Rx {
+            self.login_with_limit(name, session, 1).unwrap()
+        }
+
+        fn login_with_limit(&mut self, name: &str, session: SessionId, max_sessions: usize) -> Result<Rx, io::Error> {
+            let (tx, rx) = mpsc::unbounded_channel();
+            self.conn.add(name.to_string(), session, tx, max_sessions)?;
+            Ok(rx)
+        }
+    }
+
+    /// Applies the pending events of every node until none is left
+    fn settle(nodes: &mut [&mut Node]) {
+        loop {
+            let mut applied = false;
+            for node in nodes.iter_mut() {
+                while let Ok(event) = node.events.try_recv() {
+                    node.conn.cluster_event(event);
+                    applied = true;
+                }
+            }
+            if !applied {
+                return;
+            }
+        }
+    }
+
+    /// Commands queued to a session
+    fn received(rx: &mut Rx) -> Vec<Command> {
+        let mut commands = Vec::new();
+        while let Ok(outgoing) = rx.try_recv() {
+            if let Outgoing::Command(command, _) = outgoing {
+                commands.push(command);
+            }
+        }
+        commands
+    }
+
+    fn msg(sender: &str, target: &str, text: &str) -> Command {
+        Command::Msg(sender.to_string(), target.to_string(), text.to_string())
+    }
+
+    #[test]
+    fn event_json_round_trip() {
+        let events = vec![
+            Event::Online { instance: "n1".to_string(), user: "alice".to_string(), sessions: 2 },
+            Event::Group { instance: "n1".to_string(), group: "#g".to_string(),
+                           sender: "alice".to_string(), text: "hi".to_string() },
+            Event::Sync { instance: "n1".to_string() },
+            Event::Heartbeat { instance: "n1".to_string() },
+        ];
+        for event in events {
+            assert_eq!(Event::from_json(&event.to_json()).unwrap(), event);
+        }
+        assert!(Event::from_json(&json::object!{ "type" => "bogus", "instance" => "n1" }).is_err());
+    }
+
+    #[tokio::test]
+    async fn direct_message_to_another_instance() {
+        let hub = MemoryHub::new();
+        let (mut n1, mut n2) = (Node::new(&hub, "n1"), Node::new(&hub, "n2"));
+        let _alice = n1.login("alice", 1);
+        let mut bob = n2.login("bob", 2);
+        settle(&mut [&mut n1, &mut n2]);
+
+        n1.conn.send("alice", msg("alice", "bob", "hi bob")).await.unwrap();
+        settle(&mut [&mut n1, &mut n2]);
+        assert_eq!(received(&mut bob), vec![msg("alice", "bob", "hi bob")]);
+
+        let err = n1.conn.send("alice", msg("alice", "nobody", "hi")).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn group_message_reaches_members_on_every_instance() {
+        let hub = MemoryHub::new();
+        let (mut n1, mut n2) = (Node::new(&hub, "n1"), Node::new(&hub, "n2"));
+        let mut alice = n1.login("alice", 1);
+        let mut bob = n2.login("bob", 2);
+        let mut carol = n2.login("carol", 3);
+        n1.conn.join_group("#g", "alice").await.unwrap();
+        settle(&mut [&mut n1, &mut n2]);
+        n2.conn.join_group("#g", "bob").await.unwrap();
+        n2.conn.join_group("#g", "carol").await.unwrap();
+        settle(&mut [&mut n1, &mut n2]);
+
+        // Alice is told about the members joining from the other instance
+        let joined: Vec<Command> = received(&mut alice);
+        assert!(joined.contains(&Command::ListUsr("#g".to_string(), ListUsrOperation::Add, "\nbob".to_string())));
+        assert_eq!(n1.conn.list_group("#g").unwrap(), vec!["alice\nbob\ncarol\n".to_string()]);
+        received(&mut bob);
+        received(&mut carol);
+
+        n1.conn.send("alice", msg("alice", "#g", "hi all")).await.unwrap();
+        settle(&mut [&mut n1, &mut n2]);
+        assert_eq!(received(&mut bob), vec![msg("alice", "#g", "hi all")]);
+        assert_eq!(received(&mut carol), vec![msg("alice", "#g", "hi all")]);
+        assert!(received(&mut alice).is_empty());
+
+        // A remote member may send to the group from its own instance
+        n2.conn.send("bob", msg("bob", "#g", "hi alice")).await.unwrap();
+        settle(&mut [&mut n1, &mut n2]);
+        assert_eq!(received(&mut alice), vec![msg("bob", "#g", "hi alice")]);
+    }
+
+    #[tokio::test]
+    async fn closed_groups_are_left_on_every_instance() {
+        let hub = MemoryHub::new();
+        let (mut n1, mut n2) = (Node::new(&hub, "n1"), Node::new(&hub, "n2"));
+        let mut alice = n1.login("alice", 1);
+        let mut bob = n2.login("bob", 2);
+        n1.conn.join_group("#g", "alice").await.unwrap();
+        n2.conn.join_group("#g", "bob").await.unwrap();
+        settle(&mut [&mut n1, &mut n2]);
+        received(&mut alice);
+        received(&mut bob);
+
+        n1.conn.close_group("#g", "spam").unwrap();
+        settle(&mut [&mut n1, &mut n2]);
+        assert!(n2.conn.cluster().remote_members("#g").is_empty());
+        assert_eq!(n2.conn.list_group("#g").unwrap(), vec!["bob\n".to_string()]);
+        assert_eq!(received(&mut bob),
+                   vec![Command::ListUsr("#g".to_string(), ListUsrOperation::Remove, "\nalice".to_string())]);
+
+        n2.conn.send("bob", msg("bob", "#g", "anyone?")).await.unwrap();
+        settle(&mut [&mut n1, &mut n2]);
+        assert_eq!(received(&mut alice).len(), 1); // Only the notice of the group closing
+    }
+
+    #[tokio::test]
+    async fn presence_is_shared() {
+        let hub = MemoryHub::new();
+        let (mut n1, mut n2) = (Node::new(&hub, "n1"), Node::new(&hub, "n2"));
+        let _alice = n1.login("alice", 1);
+        settle(&mut [&mut n1, &mut n2]);
+        assert!(n2.conn.cluster().is_online("alice"));
+        assert_eq!(n2.conn.cluster().remote_users(), vec!["alice".to_string()]);
+
+        // With one session per user, the user cannot log in to another instance
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let err = n2.conn.add("alice".to_string(), 2, tx, 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        // Nor take over its session there
+        let err = n2.conn.take_over("alice", "Taken over").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(n1.conn.session_count("alice"), 1);
+
+        n1.conn.remove("alice", 1).await.unwrap();
+        settle(&mut [&mut n1, &mut n2]);
+        assert!(!n2.conn.cluster().is_online("alice"));
+        let _alice = n2.login("alice", 2);
+    }
+
+    #[tokio::test]
+    async fn resync_after_sync() {
+        let hub = MemoryHub::new();
+        let (mut n1, mut n2) = (Node::new(&hub, "n1"), Node::new(&hub, "n2"));
+        let _alice = n1.login("alice", 1);
+        let mut bob = n2.login("bob", 2);
+        n1.conn.join_group("#g", "alice").await.unwrap();
+        n2.conn.join_group("#g", "bob").await.unwrap();
+        settle(&mut [&mut n1, &mut n2]);
+        received(&mut bob);
+
+        // n1 reconnects to the backplane: the others forget its state and learn it again
+        n1.conn.cluster_event(Event::Sync { instance: "n1".to_string() });
+        settle(&mut [&mut n1, &mut n2]);
+        assert!(n2.conn.cluster().is_online("alice"));
+        assert!(n1.conn.cluster().is_online("bob"));
+        assert_eq!(n2.conn.list_group("#g").unwrap(), vec!["bob\nalice\n".to_string()]);
+
+        n1.conn.send("alice", msg("alice", "#g", "still here")).await.unwrap();
+        settle(&mut [&mut n1, &mut n2]);
+        assert!(received(&mut bob).contains(&msg("alice", "#g", "still here")));
+    }
+
+    #[tokio::test]
+    async fn silent_instances_are_forgotten() {
+        let hub = MemoryHub::new();
+        let (mut n1, mut n2) = (Node::new(&hub, "n1"), Node::new(&hub, "n2"));
+        let _alice = n1.login("alice", 1);
+        n1.conn.join_group("#g", "alice").await.unwrap();
+        settle(&mut [&mut n1, &mut n2]);
+        assert!(n2.conn.cluster().is_online("alice"));
+
+        // n2 has not heard from n1 in the timeout
+        n2.conn.cluster_heartbeat(Duration::from_secs(0));
+        assert!(!n2.conn.cluster().is_online("alice"));
+        assert!(n2.conn.cluster().remote_members("#g").is_empty());
+
+        // n1 was only stalled, its next heartbeat makes the cluster resync
+        n1.conn.cluster_heartbeat(Duration::from_secs(60));
+        settle(&mut [&mut n1, &mut n2]);
+        assert!(n2.conn.cluster().is_online("alice"));
+        assert_eq!(n2.conn.cluster().remote_members("#g"), vec!["alice".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn session_limit_counts_every_instance() {
+        let hub = MemoryHub::new();
+        let (mut n1, mut n2) = (Node::new(&hub, "n1"), Node::new(&hub, "n2"));
+        let _first = n1.login_with_limit("alice", 1, 3).unwrap();
+        let _second = n1.login_with_limit("alice", 2, 3).unwrap();
+        settle(&mut [&mut n1, &mut n2]);
+        assert_eq!(n2.conn.cluster().remote_sessions("alice"), 2);
+
+        let _third = n2.login_with_limit("alice", 3, 3).unwrap();
+        settle(&mut [&mut n1, &mut n2]);
+        let err = n1.login_with_limit("alice", 4, 3).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        n1.conn.remove("alice", 1).await.unwrap();
+        settle(&mut [&mut n1, &mut n2]);
+        assert_eq!(n2.conn.cluster().remote_sessions("alice"), 1);
+        let _fourth = n2.login_with_limit("alice", 4, 3).unwrap();
+    }
+
+    #[tokio::test]
+    async fn users_keep_their_groups_until_their_last_session() {
+        let hub = MemoryHub::new();
+        let (mut n1, mut n2) = (Node::new(&hub, "n1"), Node::new(&hub, "n2"));
+        let mut alice1 = n1.login_with_limit("alice", 1, 0).unwrap();
+        let mut alice2 = n2.login_with_limit("alice", 2, 0).unwrap();
+        let mut bob = n2.login("bob", 3);
+        n1.conn.join_group("#g", "alice").await.unwrap();
+        n2.conn.join_group("#g", "bob").await.unwrap();
+        settle(&mut [&mut n1, &mut n2]);
+        received(&mut alice1);
+        received(&mut alice2);
+        received(&mut bob);
+
+        // Every session of a user gets its direct and group messages
+        n2.conn.send("bob", msg("bob", "alice", "hi")).await.unwrap();
+        n2.conn.send("bob", msg("bob", "#g", "hi all")).await.unwrap();
+        settle(&mut [&mut n1, &mut n2]);
+        let expected = vec![msg("bob", "alice", "hi"), msg("bob", "#g", "hi all")];
+        assert_eq!(received(&mut alice1), expected);
+        assert_eq!(received(&mut alice2), expected);
+
+        // Alice joined from n1, but is still a member while logged in to n2
+        n1.conn.remove("alice", 1).await.unwrap();
+        settle(&mut [&mut n1, &mut n2]);
+        assert!(received(&mut bob).is_empty());
+        n2.conn.send("bob", msg("bob", "#g", "still there?")).await.unwrap();
+        settle(&mut [&mut n1, &mut n2]);
+        assert_eq!(received(&mut alice2), vec![msg("bob", "#g", "still there?")]);
+
+        // Its last session is closed
+        n2.conn.remove("alice", 2).await.unwrap();
+        settle(&mut [&mut n1, &mut n2]);
+        assert_eq!(received(&mut bob), vec![Command::ListUsr("#g".to_string(), ListUsrOperation::Remove, "\nalice".to_string())]);
+        assert_eq!(n1.conn.list_group("#g").unwrap(), vec!["bob\n".to_string()]);
+        assert!(n2.conn.cluster().remote_members("#g").is_empty());
+    }
+}
@@ -0,0 +1,332 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What a ban applies to
+#[derive(Debug, Clone, PartialEq)]
+pub enum BanTarget {
+    User(String),
+    Ip(IpAddr),
+    Cidr(IpAddr, u8), // Network address, prefix length
+}
+
+impl BanTarget {
+
+    /// Returns true if the given address is covered by the ban
+    pub fn matches_ip(&self, ip: IpAddr) -> bool {
+        let ip = normalize(ip);
+        match self {
+            BanTarget::Ip(banned) => normalize(*banned) == ip,
+            BanTarget::Cidr(net, prefix) => cidr_contains(*net, *prefix, ip),
+            BanTarget::User(_) => false,
+        }
+    }
+}
+
+impl FromStr for BanTarget {
+    type Err = String;
+
+    /// Parses `1.2.3.4`, `10.0.0.0/8`, `2001:db8::/32` or a username
+    fn from_str(s: &str) -> Result<BanTarget, String> {
+        if let Some(i) = s.find('/') {
+            let net = s[..i].parse::<IpAddr>()
+                .map_err(|_| format!("invalid network address in '{}'", s))?;
+            let prefix = s[i+1..].parse::<u8>()
+                .map_err(|_| format!("invalid prefix length in '{}'", s))?;
+            let max = if net.is_ipv4() { 32 } else { 128 };
+            if prefix > max {
+                return Err(format!("prefix length of '{}' is bigger than {}", s, max));
+            }
+            return Ok(BanTarget::Cidr(net, prefix));
+        }
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            return Ok(BanTarget::Ip(ip));
+        }
+        if s.is_empty() {
+            return Err("empty ban target".to_string());
+        }
+        Ok(BanTarget::User(s.to_string()))
+    }
+}
+
+impl fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BanTarget::User(name) => write!(f, "{}", name),
+            BanTarget::Ip(ip) => write!(f, "{}", ip),
+            BanTarget::Cidr(net, prefix) => write!(f, "{}/{}", net, prefix),
+        }
+    }
+}
+
+/// Converts IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) to IPv4
+fn normalize(ip: IpAddr) -> IpAddr {
+    if let IpAddr::V6(v6) = ip {
+        let s = v6.segments();
+        if s[..5] == [0, 0, 0, 0, 0] && s[5] == 0xffff {
+            let o = v6.octets();
+            return IpAddr::from([o[12], o[13], o[14], o[15]]);
+        }
+    }
+    ip
+}
+
+/// Returns true if `ip` is inside the network `net/prefix`. IPv4 clients are also matched
+/// against IPv6 networks as IPv4-mapped addresses, e.g. by `::ffff:10.0.0.0/104`.
+pub fn cidr_contains(net: IpAddr, prefix: u8, ip: IpAddr) -> bool {
+    match (net, normalize(ip)) {
+        (IpAddr::V4(net), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix.min(32))).unwrap_or(0);
+            u32::from(net) & mask == u32::from(ip) & mask
+        },
+        (IpAddr::V4(_), IpAddr::V6(_)) => false,
+        (IpAddr::V6(net), ip) => {
+            let ip = match ip {
+                IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+                IpAddr::V6(v6) => v6,
+            };
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix.min(128))).unwrap_or(0);
+            u128::from(net) & mask == u128::from(ip) & mask
+        },
+    }
+}
+
+/// Seconds since the unix epoch
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Parses durations like `90s`, `30m`, `12h`, `7d` or `2w` into seconds
+pub fn parse_duration(s: &str) -> Option<u64> {
+    if s.len() < 2 {
+        return None;
+    }
+    let (number, unit) = s.split_at(s.len() - 1);
+    let number = number.parse::<u64>().ok()?;
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    number.checked_mul(unit)
+}
+
+/// Formats a number of seconds in the largest units, e.g. `2d 3h`
+pub fn format_duration(secs: u64) -> String {
+    let units = [("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60), ("s", 1)];
+    let mut parts = Vec::new();
+    let mut rest = secs;
+    for (name, size) in units.iter() {
+        if rest >= *size {
+            parts.push(format!("{}{}", rest / size, name));
+            rest %= size;
+        }
+        if parts.len() == 2 {
+            break;
+        }
+    }
+    if parts.is_empty() { "0s".to_string() } else { parts.join(" ") }
+}
+
+#[derive(Debug, Clone)]
+pub struct Ban {
+    pub target: BanTarget,
+    pub reason: String,
+    pub created: u64,         // Unix time
+    pub expires: Option<u64>, // Unix time, None for permanent bans
+}
+
+impl Ban {
+
+    pub fn is_active(&self, now: u64) -> bool {
+        match self.expires {
+            Some(expires) => now < expires,
+            None => true,
+        }
+    }
+}
+
+impl fmt::Display for Ban {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.expires {
+            Some(expires) => write!(f, "{} (expires in {}): {}", self.target,
+                                    format_duration(expires.saturating_sub(now())), self.reason),
+            None => write!(f, "{} (permanent): {}", self.target, self.reason),
+        }
+    }
+}
+
+/// Server bans, stored in a JSON file that is rewritten on every change
+pub struct BanList {
+    path: String,
+    bans: Vec<Ban>,
+}
+
+impl BanList {
+
+    /// Loads the bans from the given file, a missing file means that there are no bans
+    pub fn load(path: &str) -> Result<BanList, io::Error> {
+        let mut list = BanList { path: path.to_string(), bans: Vec::new() };
+        if !Path::new(path).exists() {
+            return Ok(list);
+        }
+
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+        let parsed = match json::parse(&contents) {
+            Ok(p) => p,
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        };
+
+        for ban in parsed["bans"].members() {
+            let target = match ban["target"].as_str().map(BanTarget::from_str) {
+                Some(Ok(t)) => t,
+                _ => {
+                    warn!("Ignoring invalid ban entry in {}: {}", path, ban.dump());
+                    continue;
+                },
+            };
+            list.bans.push(Ban {
+                target,
+                reason: ban["reason"].as_str().unwrap_or("").to_string(),
+                created: ban["created"].as_u64().unwrap_or(0),
+                expires: ban["expires"].as_u64(),
+            });
+        }
+        Ok(list)
+    }
+
+    /// Writes the bans that did not expire to the file
+    fn save(&mut self) -> Result<(), io::Error> {
+        let now = now();
+        self.bans.retain(|b| b.is_active(now));
+
+        let mut bans = json::JsonValue::new_array();
+        for ban in &self.bans {
+            let _ = bans.push(json::object!{
+                "target" => ban.target.to_string(),
+                "reason" => ban.reason.clone(),
+                "created" => ban.created,
+                "expires" => ban.expires,
+            });
+        }
+        let contents = json::object!{ "bans" => bans }.pretty(4);
+
+        // Write to a temporary file first so that the list is never left half written
+        let tmp = format!("{}.tmp", self.path);
+        File::create(&tmp)?.write_all(contents.as_bytes())?;
+        fs::rename(&tmp, &self.path)
+    }
+
+    /// Adds a ban, replacing any existing ban with the same target
+    pub fn add(&mut self, ban: Ban) -> Result<(), io::Error> {
+        self.bans.retain(|b| b.target != ban.target);
+        self.bans.push(ban);
+        self.save()
+    }
+
+    /// Removes the ban of the target, returns false if the target was not banned
+    pub fn remove(&mut self, target: &BanTarget) -> Result<bool, io::Error> {
+        let before = self.bans.len();
+        self.bans.retain(|b| b.target != *target);
+        let removed = self.bans.len() != before;
+        self.save()?;
+        Ok(removed)
+    }
+
+    /// Returns the active ban that covers the given address, if any
+    pub fn check_ip(&self, ip: IpAddr) -> Option<&Ban> {
+        let now = now();
+        self.bans.iter().find(|b| b.is_active(now) && b.target.matches_ip(ip))
+    }
+
+    /// Returns the active ban of the given username, if any
+    pub fn check_user(&self, name: &str) -> Option<&Ban> {
+        let now = now();
+        self.bans.iter().find(|b| b.is_active(now) && b.target == BanTarget::User(name.to_string()))
+    }
+
+    /// Returns all active bans
+    pub fn list(&self) -> Vec<&Ban> {
+        let now = now();
+        self.bans.iter().filter(|b| b.is_active(now)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn networks_contain_their_addresses() {
+        assert!(cidr_contains(ip("10.0.0.0"), 8, ip("10.1.2.3")));
+        assert!(!cidr_contains(ip("10.0.0.0"), 8, ip("11.1.2.3")));
+        assert!(cidr_contains(ip("10.0.0.0"), 8, ip("::ffff:10.1.2.3")));
+        assert!(cidr_contains(ip("0.0.0.0"), 0, ip("192.168.1.1")));
+        assert!(cidr_contains(ip("192.168.1.1"), 32, ip("192.168.1.1")));
+        assert!(!cidr_contains(ip("192.168.1.1"), 32, ip("192.168.1.2")));
+        assert!(!cidr_contains(ip("10.0.0.0"), 8, ip("2001:db8::1")));
+
+        assert!(cidr_contains(ip("2001:db8::"), 32, ip("2001:db8:1::1")));
+        assert!(!cidr_contains(ip("2001:db8::"), 32, ip("2001:db9::1")));
+        assert!(!cidr_contains(ip("2001:db8::"), 32, ip("10.0.0.1")));
+        assert!(cidr_contains(ip("::"), 0, ip("10.0.0.1")));
+
+        // IPv4-mapped networks match IPv4 clients
+        assert!(cidr_contains(ip("::ffff:10.0.0.0"), 104, ip("10.1.2.3")));
+        assert!(cidr_contains(ip("::ffff:10.0.0.0"), 104, ip("::ffff:10.1.2.3")));
+        assert!(!cidr_contains(ip("::ffff:10.0.0.0"), 104, ip("11.1.2.3")));
+        assert!(cidr_contains(ip("::ffff:10.0.0.1"), 128, ip("10.0.0.1")));
+        assert!(!cidr_contains(ip("::ffff:10.0.0.1"), 128, ip("10.0.0.2")));
+        assert!(cidr_contains(ip("::ffff:0.0.0.0"), 96, ip("1.2.3.4")));
+        assert!(!cidr_contains(ip("::ffff:0.0.0.0"), 96, ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn ban_targets_are_parsed() {
+        assert_eq!("alice".parse(), Ok(BanTarget::User("alice".to_string())));
+        assert_eq!("10.0.0.1".parse(), Ok(BanTarget::Ip(ip("10.0.0.1"))));
+        assert_eq!("2001:db8::1".parse(), Ok(BanTarget::Ip(ip("2001:db8::1"))));
+        assert_eq!("10.0.0.0/8".parse(), Ok(BanTarget::Cidr(ip("10.0.0.0"), 8)));
+        assert_eq!("::ffff:10.0.0.0/104".parse(), Ok(BanTarget::Cidr(ip("::ffff:10.0.0.0"), 104)));
+        assert!("10.0.0.0/33".parse::<BanTarget>().is_err());
+        assert!("2001:db8::/129".parse::<BanTarget>().is_err());
+        assert!("10.0.0/8".parse::<BanTarget>().is_err());
+        assert!("10.0.0.0/x".parse::<BanTarget>().is_err());
+        assert!("".parse::<BanTarget>().is_err());
+
+        let ban: BanTarget = "::ffff:10.0.0.0/104".parse().unwrap();
+        assert!(ban.matches_ip(ip("10.20.30.40")));
+        assert!(!ban.matches_ip(ip("11.0.0.1")));
+        let ban: BanTarget = "::ffff:10.0.0.1".parse().unwrap();
+        assert!(ban.matches_ip(ip("10.0.0.1")));
+        assert_eq!(ban.to_string(), "::ffff:10.0.0.1");
+    }
+
+    #[test]
+    fn durations_are_parsed() {
+        assert_eq!(parse_duration("90s"), Some(90));
+        assert_eq!(parse_duration("30m"), Some(30 * 60));
+        assert_eq!(parse_duration("12h"), Some(12 * 60 * 60));
+        assert_eq!(parse_duration("7d"), Some(7 * 24 * 60 * 60));
+        assert_eq!(parse_duration("2w"), Some(2 * 7 * 24 * 60 * 60));
+        assert_eq!(parse_duration("0s"), Some(0));
+        assert_eq!(parse_duration("s"), None);
+        assert_eq!(parse_duration("10"), None);
+        assert_eq!(parse_duration("10y"), None);
+        assert_eq!(parse_duration("-1d"), None);
+        assert_eq!(parse_duration(&format!("{}w", u64::MAX)), None);
+        assert_eq!(format_duration(2 * 24 * 60 * 60 + 3 * 60 * 60 + 5), "2d 3h");
+    }
+}
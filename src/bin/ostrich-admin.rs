@@ -0,0 +1,55 @@
+use clap::{App, AppSettings, Arg, crate_version};
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::process;
+
+/// Sends a single command to the admin socket of a running ostrich server and prints the output.
+fn main() {
+    let matches = App::new("ostrich-admin")
+        .version(crate_version!())
+        .about("Control a running ostrich server through its admin socket")
+        .setting(AppSettings::TrailingVarArg)
+        .arg(Arg::with_name("socket")
+             .short("s")
+             .long("socket")
+             .value_name("PATH")
+             .default_value("ostrich.sock")
+             .help("Path of the server's admin socket"))
+        .arg(Arg::with_name("command")
+             .required(true)
+             .multiple(true)
+             .help("Command to run, e.g. 'sessions', 'kick alice spam' or 'help'"))
+        .get_matches();
+
+    let socket = matches.value_of("socket").unwrap_or("ostrich.sock");
+    let command: Vec<&str> = matches.values_of("command").map(|v| v.collect()).unwrap_or_default();
+
+    match run(socket, &command.join(" ")) {
+        Ok(true) => process::exit(0),
+        Ok(false) => process::exit(1),
+        Err(err) => {
+            eprintln!("Cannot talk to the server at {}: {}", socket, err);
+            process::exit(2);
+        },
+    }
+}
+
+/// Runs the command, returns false if the server reported an error
+fn run(socket: &str, command: &str) -> Result<bool, io::Error> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.write_all(format!("{}\n", command).as_bytes())?;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line == "OK" {
+            return Ok(true);
+        }
+        if line.starts_with("ERR: ") {
+            eprintln!("{}", &line[5..]);
+            return Ok(false);
+        }
+        println!("{}", line);
+    }
+    Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by the server"))
+}
@@ -0,0 +1,61 @@
+use clap::{App, Arg, SubCommand, crate_version};
+use log::LevelFilter;
+
+use ostrich_server::config::Overrides;
+
+use std::str::FromStr;
+
+/// Options given to the server binary in the command line
+pub struct Args {
+    pub config_path: String,
+    pub overrides: Overrides,
+    pub check_config: bool, // Only validate the config and database files
+}
+
+impl Args {
+
+    pub fn parse() -> Args {
+        let matches = App::new("ostrich-server")
+            .version(crate_version!())
+            .about("Ostrich chat server")
+            .arg(Arg::with_name("config")
+                 .short("c")
+                 .long("config")
+                 .value_name("FILE")
+                 .default_value("config.toml")
+                 .help("Path of the configuration file"))
+            .arg(Arg::with_name("address")
+                 .short("a")
+                 .long("address")
+                 .value_name("IP")
+                 .help("Overrides the listen address of the config file"))
+            .arg(Arg::with_name("port")
+                 .short("p")
+                 .long("port")
+                 .value_name("PORT")
+                 .validator(|v| v.parse::<usize>().map(|_| ())
+                            .map_err(|_| format!("invalid port: {}", v)))
+                 .help("Overrides the listen port of the config file"))
+            .arg(Arg::with_name("log-level")
+                 .short("l")
+                 .long("log-level")
+                 .value_name("LEVEL")
+                 .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+                 .case_insensitive(true)
+                 .help("Overrides the level of every logger"))
+            .subcommand(SubCommand::with_name("check-config")
+                        .about("Validates the configuration and database files and exits"))
+            .get_matches();
+
+        Args {
+            config_path: matches.value_of("config").unwrap_or("config.toml").to_string(),
+            overrides: Overrides {
+                ip_address: matches.value_of("address").map(|a| a.to_string()),
+                port: matches.value_of("port").and_then(|p| p.parse().ok()),
+                log_level: matches.value_of("log-level")
+                    .and_then(|l| LevelFilter::from_str(l).ok()),
+            },
+            check_config: matches.subcommand_matches("check-config").is_some(),
+        }
+    }
+}
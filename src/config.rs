@@ -1,16 +1,330 @@
-use serde::Deserialize;
-use toml;
+use serde::{Deserialize, Serialize};
+use toml::{self, Value, value::Table};
+use log::LevelFilter;
 
+use crate::logging::LogFormat;
+
+use std::env;
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, Read};
+
+/// Prefix of the environment variables that override config file settings
+pub const ENV_PREFIX: &str = "OSTRICH_";
 
-#[derive(Deserialize)]
+/// Server configuration. Every setting has a default value, the values are taken from (in
+/// increasing order of precedence): defaults, the config file, `OSTRICH_*` environment variables
+/// and command line `Overrides`.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct Config {
+    /// Address of the TCP listener (empty disables it)
     pub ip_address: String,
     pub port: usize,
 
+    /// Additional TCP listeners, `[[listeners]]` tables in the config file
+    pub listeners: Vec<ListenerConfig>,
+
+    /// Path of the Unix domain socket listener for local clients (empty disables it)
+    pub unix_socket: String,
+    /// File permissions of the Unix domain socket
+    pub unix_socket_mode: u32,
+
     pub logger_file: String,
     pub database_file: String,
+
+    /// Seconds a new connection has to send its log in command
+    pub login_timeout: u64,
+    /// Max number of connections waiting to log in at the same time
+    pub max_pending_logins: usize,
+    /// Max sessions a user may have at the same time in the cluster (0 for no limit)
+    pub max_sessions_per_user: usize,
+    /// Registered users over the session limit take over their oldest session instead of
+    /// being refused
+    pub session_takeover: bool,
+
+    /// Seconds between heartbeats sent to logged in users (0 disables heartbeats)
+    pub keepalive_interval: u64,
+    /// Seconds without receiving anything from a user before dropping it (0 disables it).
+    /// Must be longer than `keepalive_interval`, clients answering the heartbeats are never idle.
+    pub idle_timeout: u64,
+
+    /// Seconds to wait for sessions to close on shutdown
+    pub shutdown_timeout: u64,
+
+    /// Text file sent to users after they log in (empty disables it).
+    /// The file is read on every log in, so changes apply immediately.
+    pub motd_file: String,
+
+    /// File where server bans are stored
+    pub ban_file: String,
+
+    /// Registered users allowed to run admin commands by messaging the server
+    pub admins: Vec<String>,
+
+    /// Address of the HTTP endpoint serving metrics and health checks, e.g. "127.0.0.1:9100"
+    /// (empty disables it)
+    pub http_address: String,
+
+    /// Address of the WebSocket listener for browser clients, e.g. "0.0.0.0:9998" (empty
+    /// disables it). Clients connecting to `/json` exchange commands as JSON text messages,
+    /// on any other path as binary `ostrich-core` packets.
+    pub websocket_address: String,
+
+    /// Path of the admin control socket (empty disables it)
+    pub admin_socket: String,
+    /// File permissions of the admin socket
+    pub admin_socket_mode: u32,
+
+    /// Append-only log of security events, in JSON lines (empty disables it)
+    pub audit_file: String,
+    /// Chain the audit log entries with hashes to make tampering evident
+    pub audit_hash_chain: bool,
+
+    pub logging: LoggingConfig,
+    pub archive: ArchiveConfig,
+    pub federation: FederationConfig,
+    pub backplane: BackplaneConfig,
+
+    /// `OSTRICH_*` variables that did not match any setting and were ignored
+    #[serde(skip)]
+    pub unknown_env: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            ip_address: "127.0.0.1".to_string(),
+            port: 9999,
+            listeners: Vec::new(),
+            unix_socket: String::new(),
+            unix_socket_mode: 0o660,
+            logger_file: "server.log".to_string(),
+            database_file: "db.json".to_string(),
+            login_timeout: 30,
+            max_pending_logins: 64,
+            max_sessions_per_user: 1,
+            session_takeover: false,
+            keepalive_interval: 30,
+            idle_timeout: 0,
+            shutdown_timeout: 5,
+            motd_file: String::new(),
+            ban_file: "bans.json".to_string(),
+            admins: Vec::new(),
+            http_address: String::new(),
+            websocket_address: String::new(),
+            admin_socket: "ostrich.sock".to_string(),
+            admin_socket_mode: 0o600,
+            audit_file: "audit.log".to_string(),
+            audit_hash_chain: false,
+            logging: LoggingConfig::default(),
+            archive: ArchiveConfig::default(),
+            federation: FederationConfig::default(),
+            backplane: BackplaneConfig::default(),
+            unknown_env: Vec::new(),
+        }
+    }
+}
+
+/// A `[[listeners]]` table of the config file
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct ListenerConfig {
+    /// Address to bind, e.g. "0.0.0.0:9999" or "[::]:9999"
+    pub address: String,
+    /// Only accept IPv6 clients on IPv6 addresses, by default they also accept IPv4 clients
+    pub v6_only: bool,
+    /// Accept WebSocket clients instead of raw `ostrich-core` clients
+    pub websocket: bool,
+    /// PEM certificate chain and private key, TLS is enabled when both are set
+    pub tls_cert: String,
+    pub tls_key: String,
+    /// Read a PROXY protocol (v1 or v2) header from connections of trusted proxies and use
+    /// the client address it carries
+    pub proxy_protocol: bool,
+    /// Addresses or networks of the proxies, e.g. ["10.0.0.0/8"]. Other clients of the
+    /// listener are handled as direct connections.
+    pub trusted_proxies: Vec<String>,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> ListenerConfig {
+        ListenerConfig {
+            address: String::new(),
+            v6_only: false,
+            websocket: false,
+            tls_cert: String::new(),
+            tls_key: String::new(),
+            proxy_protocol: false,
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+impl ListenerConfig {
+
+    pub fn is_tls(&self) -> bool {
+        !self.tls_cert.is_empty() && !self.tls_key.is_empty()
+    }
+}
+
+/// `[logging]` section of the config file
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// Log to the terminal, disable it when running as a daemon
+    pub terminal: bool,
+    pub terminal_level: LevelFilter,
+    pub file_level: LevelFilter,
+    /// Format of the log records, `text` or `json` (one object per line)
+    pub format: LogFormat,
+    /// Rotate the log file when it reaches this size in bytes (0 disables)
+    pub max_size: u64,
+    /// Rotate the log file every given seconds (0 disables)
+    pub rotate_interval: u64,
+    /// Number of rotated log files to keep
+    pub retention: usize,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> LoggingConfig {
+        LoggingConfig {
+            terminal: true,
+            terminal_level: LevelFilter::Trace,
+            file_level: LevelFilter::Info,
+            format: LogFormat::Text,
+            max_size: 10 * 1024 * 1024,
+            rotate_interval: 0,
+            retention: 5,
+        }
+    }
+}
+
+/// `[archive]` section of the config file, transcripts of group conversations
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ArchiveConfig {
+    /// Archive every group
+    pub all_groups: bool,
+    /// Groups to archive when `all_groups` is false, e.g. ["#support"]
+    pub groups: Vec<String>,
+    /// Directory of the transcript files
+    pub directory: String,
+    /// Formats of the transcripts, `text` and/or `json`
+    pub formats: Vec<LogFormat>,
+    /// Rotate a transcript when it reaches this size in bytes (0 disables)
+    pub max_size: u64,
+    /// Rotate a transcript every given seconds (0 disables)
+    pub rotate_interval: u64,
+    /// Number of rotated transcripts to keep per group
+    pub retention: usize,
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> ArchiveConfig {
+        ArchiveConfig {
+            all_groups: false,
+            groups: Vec::new(),
+            directory: "archive".to_string(),
+            formats: vec![LogFormat::Text],
+            max_size: 10 * 1024 * 1024,
+            rotate_interval: 24 * 60 * 60,
+            retention: 30,
+        }
+    }
+}
+
+/// `[federation]` section of the config file, links to other servers
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct FederationConfig {
+    /// Name of this server in the federation, users of linked servers reach its users as
+    /// `user@server_name` (empty disables federation)
+    pub server_name: String,
+    /// Address accepting links from other servers, e.g. "0.0.0.0:7999" (empty disables it)
+    pub address: String,
+    /// Seconds between pings on a link, a link silent for three intervals is dropped
+    pub ping_interval: u64,
+    /// Seconds to wait before connecting again to a peer after the link is lost
+    pub reconnect_interval: u64,
+    /// Servers allowed to link with this one, `[[federation.peers]]` tables
+    pub peers: Vec<PeerConfig>,
+}
+
+impl Default for FederationConfig {
+    fn default() -> FederationConfig {
+        FederationConfig {
+            server_name: String::new(),
+            address: String::new(),
+            ping_interval: 30,
+            reconnect_interval: 10,
+            peers: Vec::new(),
+        }
+    }
+}
+
+/// A `[[federation.peers]]` table of the config file
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+#[serde(default)]
+pub struct PeerConfig {
+    /// Name of the peer server, as set in its `server_name`
+    pub name: String,
+    /// Address to connect to, e.g. "10.0.0.2:7999" (empty waits for the peer to connect)
+    pub address: String,
+    /// Secret shared by both servers, used to authenticate the link
+    pub secret: String,
+}
+
+/// `[backplane]` section of the config file, shares the users and groups of the instances of
+/// a cluster through Redis
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct BackplaneConfig {
+    /// Address of the Redis server, e.g. "127.0.0.1:6379" (empty disables the backplane)
+    pub redis_address: String,
+    pub redis_password: String,
+    /// Prefix of the Redis channel, to share a Redis server between clusters
+    pub prefix: String,
+    /// Name of this instance, unique in the cluster
+    pub instance: String,
+    /// Seconds between the heartbeats of the instance, instances that miss 3 heartbeats in a
+    /// row are considered gone and their users offline
+    pub heartbeat_interval: u64,
+}
+
+impl Default for BackplaneConfig {
+    fn default() -> BackplaneConfig {
+        BackplaneConfig {
+            redis_address: String::new(),
+            redis_password: String::new(),
+            prefix: "ostrich:".to_string(),
+            instance: String::new(),
+            heartbeat_interval: 10,
+        }
+    }
+}
+
+/// Settings given outside the configuration file, they take precedence over the file
+#[derive(Default, Clone)]
+pub struct Overrides {
+    pub ip_address: Option<String>,
+    pub port: Option<usize>,
+    pub log_level: Option<LevelFilter>, // Level of the terminal and the log file
+}
+
+impl Overrides {
+
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(ip_address) = &self.ip_address {
+            config.ip_address = ip_address.clone();
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(level) = self.log_level {
+            config.logging.terminal_level = level;
+            config.logging.file_level = level;
+        }
+    }
 }
 
 impl Config {
@@ -21,7 +335,255 @@ impl Config {
         let mut contents = String::new();
         file.read_to_string(&mut contents)?;
 
-        // Deserialize the config string
-        Ok(toml::from_str(&contents)?)
+        // Parse the config string and apply the environment overrides on top
+        let mut table: Table = toml::from_str(&contents)?;
+        let unknown_env = apply_env(&mut table, env::vars());
+
+        let mut config: Config = Value::Table(table).try_into()?;
+        config.unknown_env = unknown_env;
+        Ok(config)
+    }
+
+    /// Returns every TCP listener: the ones of `ip_address`/`port` and `websocket_address` if
+    /// set, followed by the `listeners` list.
+    pub fn all_listeners(&self) -> Vec<ListenerConfig> {
+        let mut listeners = Vec::new();
+        if !self.ip_address.is_empty() {
+            // IPv6 addresses must be in brackets when followed by a port
+            let address = if self.ip_address.contains(':') && !self.ip_address.starts_with('[') {
+                format!("[{}]:{}", self.ip_address, self.port)
+            } else {
+                format!("{}:{}", self.ip_address, self.port)
+            };
+            listeners.push(ListenerConfig { address, ..ListenerConfig::default() });
+        }
+        if !self.websocket_address.is_empty() {
+            listeners.push(ListenerConfig {
+                address: self.websocket_address.clone(),
+                websocket: true,
+                ..ListenerConfig::default()
+            });
+        }
+        listeners.extend(self.listeners.iter().cloned());
+        listeners
+    }
+
+    /// Fails if the heartbeat settings would drop the clients that answer every heartbeat
+    pub fn check_keepalive(&self) -> Result<(), io::Error> {
+        if self.keepalive_interval > 0 && self.idle_timeout > 0
+            && self.idle_timeout <= self.keepalive_interval {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("idle_timeout ({}) must be longer than keepalive_interval ({})",
+                        self.idle_timeout, self.keepalive_interval)));
+        }
+        Ok(())
+    }
+
+    /// Returns the names of the settings that differ from `new` and cannot be applied to a
+    /// running server.
+    pub fn restart_required(&self, new: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.ip_address != new.ip_address {
+            changed.push("ip_address");
+        }
+        if self.port != new.port {
+            changed.push("port");
+        }
+        if self.listeners != new.listeners {
+            changed.push("listeners");
+        }
+        if self.unix_socket != new.unix_socket || self.unix_socket_mode != new.unix_socket_mode {
+            changed.push("unix_socket");
+        }
+        if self.logger_file != new.logger_file {
+            changed.push("logger_file");
+        }
+        if self.ban_file != new.ban_file {
+            changed.push("ban_file");
+        }
+        if self.websocket_address != new.websocket_address {
+            changed.push("websocket_address");
+        }
+        if self.http_address != new.http_address {
+            changed.push("http_address");
+        }
+        if self.admin_socket != new.admin_socket || self.admin_socket_mode != new.admin_socket_mode {
+            changed.push("admin_socket");
+        }
+        if self.audit_file != new.audit_file || self.audit_hash_chain != new.audit_hash_chain {
+            changed.push("audit_file");
+        }
+        // Log levels, format and terminal output are applied to the running logger
+        if self.logging.max_size != new.logging.max_size
+            || self.logging.rotate_interval != new.logging.rotate_interval
+            || self.logging.retention != new.logging.retention {
+            changed.push("logging");
+        }
+        if self.archive != new.archive {
+            changed.push("archive");
+        }
+        if self.federation != new.federation {
+            changed.push("federation");
+        }
+        if self.backplane != new.backplane {
+            changed.push("backplane");
+        }
+        changed
+    }
+
+    /// Copies the settings that only take effect after a restart (see `restart_required`) from
+    /// the running configuration, so they keep describing what the server actually uses
+    pub fn keep_restart_only(&mut self, running: &Config) {
+        self.ip_address = running.ip_address.clone();
+        self.port = running.port;
+        self.listeners = running.listeners.clone();
+        self.unix_socket = running.unix_socket.clone();
+        self.unix_socket_mode = running.unix_socket_mode;
+        self.logger_file = running.logger_file.clone();
+        self.ban_file = running.ban_file.clone();
+        self.websocket_address = running.websocket_address.clone();
+        self.http_address = running.http_address.clone();
+        self.admin_socket = running.admin_socket.clone();
+        self.admin_socket_mode = running.admin_socket_mode;
+        self.audit_file = running.audit_file.clone();
+        self.audit_hash_chain = running.audit_hash_chain;
+        self.logging.max_size = running.logging.max_size;
+        self.logging.rotate_interval = running.logging.rotate_interval;
+        self.logging.retention = running.logging.retention;
+        self.archive = running.archive.clone();
+        self.federation = running.federation.clone();
+        self.backplane = running.backplane.clone();
+    }
+}
+
+/// Sets the values of the `OSTRICH_*` variables in the parsed config file. Variables are mapped
+/// to settings by lowercasing the name without the prefix, settings inside a section are prefixed
+/// with the section name: `OSTRICH_PORT` sets `port` and `OSTRICH_LOGGING_FILE_LEVEL` sets
+/// `file_level` in `[logging]`. Values of string settings are taken as they are, the others are
+/// parsed as TOML values. Returns the variables that do not match any setting, they are ignored.
+fn apply_env<I: Iterator<Item = (String, String)>>(table: &mut Table, vars: I) -> Vec<String> {
+    // Sections and value types are taken from the defaults, every setting has one
+    let defaults = match Value::try_from(Config::default()) {
+        Ok(Value::Table(t)) => t,
+        _ => Table::new(),
+    };
+    let mut unknown = Vec::new();
+
+    for (var, raw) in vars {
+        if !var.starts_with(ENV_PREFIX) {
+            continue;
+        }
+        let name = var[ENV_PREFIX.len()..].to_lowercase();
+
+        let section = defaults.iter()
+            .filter(|(_, v)| v.is_table())
+            .map(|(k, _)| k)
+            .find(|k| name.starts_with(&format!("{}_", k)) && !defaults.contains_key(&name));
+        let (section, key) = match section {
+            Some(section) => (Some(section), name[section.len() + 1..].to_string()),
+            None => (None, name),
+        };
+        let default = match section {
+            Some(section) => defaults.get(section).and_then(|t| t.get(key.as_str())),
+            None => defaults.get(&key),
+        };
+        let value = match default {
+            Some(default) => env_value(&raw, default),
+            None => {
+                unknown.push(var);
+                continue;
+            },
+        };
+
+        match section {
+            Some(section) => {
+                let entry = table.entry(section.clone())
+                    .or_insert_with(|| Value::Table(Table::new()));
+                if let Value::Table(t) = entry {
+                    t.insert(key, value);
+                }
+            },
+            None => { table.insert(key, value); },
+        }
+    }
+    unknown
+}
+
+/// Converts the value of an environment variable to the type of the setting's default value.
+/// Values that are not valid TOML are kept as strings, deserializing the config reports them.
+fn env_value(raw: &str, default: &Value) -> Value {
+    if default.is_str() {
+        return Value::String(raw.to_string());
+    }
+    toml::from_str::<Table>(&format!("v = {}", raw)).ok()
+        .and_then(|mut t| t.remove("v"))
+        .unwrap_or_else(|| Value::String(raw.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reloads_keep_the_settings_that_need_a_restart() {
+        let running = Config::default();
+        let mut new: Config = toml::from_str(r#"
+            ip_address = "0.0.0.0"
+            port = 7000
+            unix_socket = "ostrich.sock"
+            logger_file = "other.log"
+            ban_file = "other.json"
+            websocket_address = "0.0.0.0:7001"
+            http_address = "0.0.0.0:7002"
+            admin_socket = "other.sock"
+            audit_file = "other.audit"
+            audit_hash_chain = true
+            max_sessions_per_user = 3
+            [[listeners]]
+            address = "0.0.0.0:7003"
+            [logging]
+            file_level = "trace"
+            max_size = 1024
+            [archive]
+            all_groups = true
+            [federation]
+            server_name = "a"
+            [backplane]
+            instance = "n1"
+        "#).unwrap();
+        assert_eq!(running.restart_required(&new).len(), 14);
+
+        new.keep_restart_only(&running);
+        assert!(running.restart_required(&new).is_empty());
+        assert_eq!(new.max_sessions_per_user, 3);
+        assert_eq!(new.logging.file_level, LevelFilter::Trace);
+    }
+
+    #[test]
+    fn environment_values_take_the_type_of_their_setting() {
+        let mut table: Table = toml::from_str("port = 7000").unwrap();
+        let vars = vec![
+            ("OSTRICH_LOGGER_FILE", "2024"),
+            ("OSTRICH_PORT", "9000"),
+            ("OSTRICH_SESSION_TAKEOVER", "true"),
+            ("OSTRICH_LOGGING_FILE_LEVEL", "debug"),
+            ("OSTRICH_FEDERATION_SERVER_NAME", "1"),
+            ("OSTRICH_PROT", "9001"),
+            ("OSTRICH_LOGGING_COLOUR", "yes"),
+            ("PATH", "/bin"),
+        ];
+        let unknown = apply_env(&mut table,
+            vars.into_iter().map(|(k, v)| (k.to_string(), v.to_string())));
+        assert_eq!(unknown, vec!["OSTRICH_PROT", "OSTRICH_LOGGING_COLOUR"]);
+
+        let config: Config = Value::Table(table).try_into().unwrap();
+        assert_eq!(config.logger_file, "2024");
+        assert_eq!(config.port, 9000);
+        assert!(config.session_takeover);
+        assert_eq!(config.federation.server_name, "1");
+
+        let mut table = Table::new();
+        apply_env(&mut table, vec![("OSTRICH_PORT".to_string(), "high".to_string())].into_iter());
+        assert!(Value::Table(table).try_into::<Config>().is_err());
     }
 }
@@ -0,0 +1,1069 @@
+use ostrich_core::{Command, ListUsrOperation};
+
+use hmac::{Hmac, Mac, NewMac};
+use json::JsonValue;
+use sha2::Sha256;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Mutex, mpsc};
+use tokio::time::{self, Duration};
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::SharedConn;
+use crate::audit::AuditLog;
+use crate::config::FederationConfig;
+
+/// Max length of a line of the server protocol
+const MAX_LINE: u64 = 16 * 1024;
+/// Seconds a new link has to complete the handshake
+const HANDSHAKE_TIMEOUT: u64 = 10;
+
+/// Queues lines of the server protocol to a linked server
+type LinkTx = mpsc::UnboundedSender<String>;
+
+/// A direct link with another server
+struct Link {
+    id: u64,           // Tells the connections with the same server apart
+    tx: LinkTx,
+    initiator: String, // Server that opened the connection
+}
+
+/// State of the links with other servers. Servers exchange JSON lines: after a handshake
+/// authenticated with a shared secret, every line is signed with a key of the link (see
+/// `LinkKey`). They announce the servers reachable through them and
+/// the members of their groups, and relay messages addressed to `user@server` or to groups.
+///
+/// Linked servers must form a tree, a link or announcement of a server that is already
+/// reachable is refused, so there is a single route to every server. Flooded messages also
+/// carry the servers they went through, and are dropped if they come back. When two servers
+/// connect to each other at the same time, both keep the connection opened by the server
+/// with the lowest name.
+pub struct Federation {
+    name: String,                          // Name of this server, empty when not federated
+    links: HashMap<String, Link>,          // Directly linked servers
+    last_link: u64,                        // Id of the last link
+    routes: HashMap<String, String>,       // Every reachable server, link it is reached through
+    members: HashMap<String, Vec<String>>, // Group name, members on other servers (user@server)
+}
+
+/// Splits a `user@server` address, None for local names
+pub fn split_address(address: &str) -> Option<(&str, &str)> {
+    let i = address.rfind('@')?;
+    Some((&address[..i], &address[i + 1..]))
+}
+
+impl Federation {
+
+    pub fn new(name: &str) -> Federation {
+        Federation {
+            name: name.to_string(),
+            links: HashMap::new(),
+            last_link: 0,
+            routes: HashMap::new(),
+            members: HashMap::new(),
+        }
+    }
+
+    /// A server that is not part of any federation
+    pub fn disabled() -> Federation {
+        Federation::new("")
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the reachable servers and the linked server each one is reached through, sorted
+    pub fn servers(&self) -> Vec<(String, String)> {
+        let mut servers: Vec<(String, String)> = self.routes.iter()
+            .map(|(server, link)| (server.clone(), link.clone()))
+            .collect();
+        servers.sort();
+        servers
+    }
+
+    /// Returns true if the server is linked, directly or through other servers
+    pub fn is_reachable(&self, server: &str) -> bool {
+        self.routes.contains_key(server)
+    }
+
+    /// Members of a group on other servers, as `user@server`
+    pub fn remote_members(&self, group: &str) -> &[String] {
+        match self.members.get(group) {
+            Some(members) => members,
+            None => &[],
+        }
+    }
+
+    /// Relays a message from a local user to a user of another server
+    pub fn send_direct(&self, sender: &str, target: &str, text: &str) -> Result<(), io::Error> {
+        let server = split_address(target).map(|(_, s)| s).unwrap_or("");
+        self.send_to(server, json::object!{
+            "type" => "msg",
+            "sender" => self.address(sender),
+            "target" => target,
+            "text" => text,
+        })
+    }
+
+    /// Relays a message sent by a local user to a group to the rest of the servers
+    pub fn group_message(&self, group: &str, sender: &str, text: &str) {
+        self.flood(None, json::object!{
+            "type" => "group",
+            "group" => group,
+            "sender" => self.address(sender),
+            "text" => text,
+        });
+    }
+
+    /// Announces that a local user joined a group
+    pub fn joined(&self, group: &str, user: &str) {
+        self.flood(None, json::object!{ "type" => "join", "group" => group, "user" => self.address(user) });
+    }
+
+    /// Announces that a local user left a group
+    pub fn left(&self, group: &str, user: &str) {
+        self.flood(None, json::object!{ "type" => "leave", "group" => group, "user" => self.address(user) });
+    }
+
+    /// Address of a local user for the users of other servers
+    fn address(&self, user: &str) -> String {
+        format!("{}@{}", user, self.name)
+    }
+
+    /// Sends a message to a server through the link it is reached through
+    fn send_to(&self, server: &str, msg: JsonValue) -> Result<(), io::Error> {
+        let link = self.routes.get(server).and_then(|link| self.links.get(link));
+        match link {
+            Some(link) => link.tx.send(msg.dump())
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe,
+                                            format!("Link to server {} lost", server))),
+            None => Err(io::Error::new(io::ErrorKind::NotFound,
+                                       format!("Server {} is not linked", server))),
+        }
+    }
+
+    /// Sends a message to every linked server, except the one it came from and the ones it
+    /// already went through
+    fn flood(&self, from: Option<&str>, mut msg: JsonValue) {
+        if self.links.is_empty() {
+            return;
+        }
+        if !msg["path"].is_array() {
+            msg["path"] = JsonValue::new_array();
+        }
+        let _ = msg["path"].push(self.name.as_str());
+
+        let line = msg.dump();
+        for (server, link) in &self.links {
+            if Some(server.as_str()) == from || msg["path"].contains(server.as_str()) {
+                continue;
+            }
+            let _ = link.tx.send(line.clone());
+        }
+    }
+}
+
+impl SharedConn {
+
+    /// Registers an authenticated link, announcing the new server to the rest of the network
+    /// and sending it the servers and group members known here. `outgoing` is true if this
+    /// server opened the connection. Returns the id of the link.
+    fn link_up(&mut self, server: &str, tx: LinkTx, outgoing: bool) -> Result<u64, io::Error> {
+        let initiator = if outgoing { self.federation.name.clone() } else { server.to_string() };
+
+        // Both servers connected to each other, or the server reconnected before the previous
+        // connection timed out here
+        if let Some(link) = self.federation.links.get(server) {
+            if initiator > link.initiator {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                    format!("Server {} is already linked by a connection from {}", server, link.initiator)));
+            }
+            let close = json::object!{ "type" => "close", "reason" => "Replaced by another connection" };
+            let _ = link.tx.send(close.dump());
+            let id = link.id;
+            info!("Replacing the connection with {} by one from {}", server, initiator);
+            self.link_down(server, id);
+        }
+
+        let federation = &mut self.federation;
+        if server == federation.name || federation.routes.contains_key(server) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                      format!("Server {} is already linked", server)));
+        }
+
+        // Servers and members reachable through other links
+        let mut burst = Vec::new();
+        for remote in federation.routes.keys() {
+            burst.push(json::object!{ "type" => "server", "name" => remote.as_str() });
+        }
+        for (group, users) in &self.groups {
+            for user in users {
+                burst.push(json::object!{
+                    "type" => "join", "group" => group.as_str(), "user" => federation.address(user),
+                });
+            }
+        }
+        for (group, users) in &federation.members {
+            for user in users {
+                burst.push(json::object!{ "type" => "join", "group" => group.as_str(), "user" => user.as_str() });
+            }
+        }
+        for mut msg in burst {
+            msg["path"] = json::array![federation.name.as_str()];
+            let _ = tx.send(msg.dump());
+        }
+
+        federation.flood(None, json::object!{ "type" => "server", "name" => server });
+        federation.last_link += 1;
+        let id = federation.last_link;
+        federation.links.insert(server.to_string(), Link { id, tx, initiator });
+        federation.routes.insert(server.to_string(), server.to_string());
+        Ok(id)
+    }
+
+    /// Returns true if the link is the current one with the server
+    fn is_current_link(&self, server: &str, id: u64) -> bool {
+        self.federation.links.get(server).map(|link| link.id) == Some(id)
+    }
+
+    /// Forgets a lost link and every server reached through it (a netsplit). Does nothing if
+    /// the link was already replaced.
+    fn link_down(&mut self, server: &str, id: u64) {
+        if !self.is_current_link(server, id) {
+            return;
+        }
+        self.federation.links.remove(server);
+        let lost: Vec<String> = self.federation.routes.iter()
+            .filter(|(_, link)| link.as_str() == server)
+            .map(|(remote, _)| remote.clone())
+            .collect();
+
+        if lost.len() > 1 {
+            warn!("Netsplit, servers unreachable: {}", lost.join(", "));
+        }
+        for remote in lost {
+            self.server_lost(&remote, server);
+        }
+    }
+
+    /// Forgets a server that is no longer reachable, and its users
+    fn server_lost(&mut self, server: &str, link: &str) {
+        self.federation.routes.remove(server);
+
+        let suffix = format!("@{}", server);
+        let mut gone = Vec::new();
+        for (group, users) in self.federation.members.iter_mut() {
+            users.retain(|user| if user.ends_with(&suffix) {
+                gone.push((group.clone(), user.clone()));
+                false
+            } else {
+                true
+            });
+        }
+        self.federation.members.retain(|_, users| !users.is_empty());
+        for (group, user) in gone {
+            self.notify_group(&group, ListUsrOperation::Remove, &user);
+        }
+
+        self.federation.flood(Some(link), json::object!{ "type" => "squit", "name" => server });
+    }
+
+    /// Handles a message received from a linked server. Errors are protocol violations that
+    /// end the link.
+    fn link_message(&mut self, link: &str, msg: JsonValue) -> Result<(), io::Error> {
+        if msg["path"].contains(self.federation.name.as_str()) {
+            warn!("Dropping message from {} that went through this server already", link);
+            return Ok(());
+        }
+        let field = |name: &str| match msg[name].as_str() {
+            Some(value) => Ok(value.to_string()),
+            None => Err(invalid(&format!("missing {} in {} message", name, msg["type"]))),
+        };
+
+        match msg["type"].as_str().unwrap_or("") {
+            "ping" => (),
+
+            "server" => {
+                let server = field("name")?;
+                if server == self.federation.name || self.federation.routes.contains_key(&server) {
+                    return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                        format!("Server {} announced by {} is already linked, the link would \
+                                 form a loop", server, link)));
+                }
+                info!("Server {} linked through {}", server, link);
+                self.federation.routes.insert(server, link.to_string());
+                self.federation.flood(Some(link), msg);
+            },
+
+            "squit" => {
+                let server = field("name")?;
+                if self.federation.routes.get(&server).map(|l| l.as_str()) == Some(link) {
+                    warn!("Server {} unreachable, lost by {}", server, link);
+                    self.server_lost(&server, link);
+                }
+            },
+
+            "join" | "leave" => {
+                let group = field("group")?;
+                let user = field("user")?;
+                if !self.is_routed_through(&user, link) {
+                    debug!("Ignoring {} of {} to {} from {}", msg["type"], user, group, link);
+                    return Ok(());
+                }
+                let members = self.federation.members.entry(group.clone()).or_default();
+                let known = members.contains(&user);
+                let operation = if msg["type"] == "join" && !known {
+                    members.push(user.clone());
+                    Some(ListUsrOperation::Add)
+                } else if msg["type"] == "leave" && known {
+                    members.retain(|u| *u != user);
+                    Some(ListUsrOperation::Remove)
+                } else {
+                    None
+                };
+                self.federation.members.retain(|_, users| !users.is_empty());
+                if let Some(operation) = operation {
+                    self.notify_group(&group, operation, &user);
+                }
+                self.federation.flood(Some(link), msg);
+            },
+
+            "group" => {
+                let group = field("group")?;
+                let sender = field("sender")?;
+                let text = field("text")?;
+                if !self.is_routed_through(&sender, link) {
+                    debug!("Ignoring group message of {} to {} from {}", sender, group, link);
+                    return Ok(());
+                }
+                let command = Command::Msg(sender.clone(), group.clone(), text.clone());
+                self.deliver_to_group(&group, &command, &sender);
+                if self.groups.contains_key(&group) {
+                    self.archive.record(&group, &sender, &text);
+                }
+                let members = self.groups.get(&group).map_or(&[][..], |users| users.as_slice());
+                self.cluster.group_message(&group, members, &sender, &text);
+                self.federation.flood(Some(link), msg);
+            },
+
+            "msg" => {
+                let sender = field("sender")?;
+                let target = field("target")?;
+                let text = field("text")?;
+                // Servers may only relay messages of the servers reached through them
+                if !self.is_routed_through(&sender, link) {
+                    debug!("Ignoring message of {} to {} from {}", sender, target, link);
+                    return Ok(());
+                }
+                match split_address(&target) {
+                    Some((user, server)) if server == self.federation.name => {
+                        // The user may also be logged in to other instances of the cluster
+                        let remote = self.cluster.is_online(user);
+                        if remote {
+                            self.cluster.send_direct(&sender, user, &text);
+                        }
+                        let command = Command::Msg(sender.clone(), user.to_string(), text);
+                        let delivered = self.deliver(user, command) || remote;
+                        if !delivered {
+                            self.reply_error(&sender, &format!("Target {} not connected or does not exist", target));
+                        }
+                    },
+                    Some((_, server)) => {
+                        if let Err(err) = self.federation.send_to(server, msg.clone()) {
+                            self.reply_error(&sender, &err.to_string());
+                        }
+                    },
+                    None => return Err(invalid("msg target is not user@server")),
+                }
+            },
+
+            "error" => {
+                let target = field("target")?;
+                let text = field("text")?;
+                match split_address(&target) {
+                    Some((user, server)) if server == self.federation.name => {
+                        self.deliver(user, Command::Err(format!("unable to send message: {}", text)));
+                    },
+                    Some((_, server)) => {
+                        let _ = self.federation.send_to(server, msg.clone());
+                    },
+                    None => return Err(invalid("error target is not user@server")),
+                }
+            },
+
+            "close" => {
+                return Err(io::Error::new(io::ErrorKind::ConnectionAborted,
+                                          format!("closed by {}: {}", link, msg["reason"])));
+            },
+
+            other => debug!("Ignoring unknown message type '{}' from {}", other, link),
+        }
+        Ok(())
+    }
+
+    /// Returns true if the server of a `user@server` address is reached through the link
+    fn is_routed_through(&self, address: &str, link: &str) -> bool {
+        match split_address(address) {
+            Some((_, server)) => self.federation.routes.get(server).map(|l| l.as_str()) == Some(link),
+            None => false,
+        }
+    }
+
+    /// Tells the sender of an undeliverable message about it, through its server
+    fn reply_error(&self, sender: &str, text: &str) {
+        if let Some((_, server)) = split_address(sender) {
+            let _ = self.federation.send_to(server, json::object!{
+                "type" => "error", "target" => sender, "text" => text,
+            });
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid server message: {}", msg))
+}
+
+/// Checks the federation settings
+pub fn check_config(config: &FederationConfig) -> Result<(), io::Error> {
+    let valid_name = |name: &str| {
+        !name.is_empty() && !name.contains(|c: char| c == '@' || c == '#' || c == '!' || c.is_whitespace())
+    };
+    if !valid_name(&config.server_name) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  format!("invalid server_name '{}'", config.server_name)));
+    }
+    for peer in &config.peers {
+        if !valid_name(&peer.name) || peer.name == config.server_name {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("invalid peer name '{}'", peer.name)));
+        }
+        if peer.secret.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("peer {} has no secret", peer.name)));
+        }
+    }
+    Ok(())
+}
+
+/// Starts linking with the peer servers: accepts links on the configured address and keeps
+/// connecting to the peers that have one. Returns the address links are accepted on, if any.
+pub async fn start(config: FederationConfig,
+                   shared_conn: Arc<Mutex<SharedConn>>,
+                   audit: Arc<AuditLog>) -> Result<Option<SocketAddr>, io::Error> {
+    check_config(&config)?;
+    let config = Arc::new(config);
+
+    let mut local_addr = None;
+    if !config.address.is_empty() {
+        let mut listener = TcpListener::bind(&config.address).await?;
+        local_addr = Some(listener.local_addr()?);
+
+        let config = Arc::clone(&config);
+        let shared_conn = Arc::clone(&shared_conn);
+        let audit = Arc::clone(&audit);
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = match listener.accept().await {
+                    Ok(s) => s,
+                    Err(err) => {
+                        warn!("Cannot accept server link: {}", err);
+                        continue;
+                    },
+                };
+                let config = Arc::clone(&config);
+                let shared_conn = Arc::clone(&shared_conn);
+                let audit = Arc::clone(&audit);
+                tokio::spawn(async move {
+                    if let Err(err) = link(stream, addr, None, &config, &shared_conn, &audit).await {
+                        warn!("Link from {} ended: {}", addr, err);
+                    }
+                });
+            }
+        });
+    }
+
+    for peer in config.peers.iter().filter(|p| !p.address.is_empty()) {
+        let name = peer.name.clone();
+        let address = peer.address.clone();
+        let config = Arc::clone(&config);
+        let shared_conn = Arc::clone(&shared_conn);
+        let audit = Arc::clone(&audit);
+        tokio::spawn(async move {
+            loop {
+                // The peer may have connected to us, or be reachable through another server
+                if !shared_conn.lock().await.federation.is_reachable(&name) {
+                    if let Err(err) = connect(&address, &name, &config, &shared_conn, &audit).await {
+                        warn!("Link to {} ({}) ended: {}", name, address, err);
+                    }
+                }
+                time::delay_for(Duration::from_secs(config.reconnect_interval)).await;
+            }
+        });
+    }
+    Ok(local_addr)
+}
+
+async fn connect(address: &str,
+                 name: &str,
+                 config: &FederationConfig,
+                 shared_conn: &Mutex<SharedConn>,
+                 audit: &AuditLog) -> Result<(), io::Error> {
+    let stream = TcpStream::connect(address).await?;
+    let addr = stream.peer_addr()?;
+    link(stream, addr, Some(name), config, shared_conn, audit).await
+}
+
+/// Runs a link with another server until it is lost. `expected` is the name of the server
+/// connected to, None for links accepted from other servers.
+async fn link(stream: TcpStream,
+              addr: SocketAddr,
+              expected: Option<&str>,
+              config: &FederationConfig,
+              shared_conn: &Mutex<SharedConn>,
+              audit: &AuditLog) -> Result<(), io::Error> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+
+    let handshake = time::timeout(Duration::from_secs(HANDSHAKE_TIMEOUT),
+                                  handshake(&mut reader, &mut writer, expected, config));
+    let (server, mut signer, verifier) = match handshake.await {
+        Ok(Ok(keys)) => keys,
+        Ok(Err(err)) => {
+            audit.record("link_failure", json::object!{
+                "address" => addr.to_string(), "reason" => err.to_string(),
+            });
+            return Err(err);
+        },
+        Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "handshake timeout")),
+    };
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    let id = match shared_conn.lock().await.link_up(&server, tx, expected.is_some()) {
+        Ok(id) => id,
+        Err(err) => {
+            let close = json::object!{ "type" => "close", "reason" => err.to_string() };
+            let _ = writer.write_all(signer.sign(&close.dump()).as_bytes()).await;
+            return Err(err);
+        },
+    };
+    info!("Linked to server {} ({})", server, addr);
+    audit.record("link", json::object!{ "server" => server.as_str(), "address" => addr.to_string() });
+
+    let ping_interval = Duration::from_secs(config.ping_interval.max(1));
+    tokio::spawn(write_link(writer, rx, signer, ping_interval));
+
+    let result = read_link(&mut reader, &server, id, verifier, shared_conn, ping_interval * 3).await;
+    let mut conn = shared_conn.lock().await;
+    if conn.is_current_link(&server, id) {
+        conn.link_down(&server, id);
+        warn!("Link to server {} lost", server);
+    }
+    result
+}
+
+/// Exchanges the `hello` and `auth` messages. Each server sends a random nonce and proves
+/// that it knows the secret by answering with an HMAC of both names and both nonces. Returns
+/// the name of the other server, and the keys to sign the lines sent to it and to verify the
+/// lines received from it.
+async fn handshake<R, W>(reader: &mut R,
+                         writer: &mut W,
+                         expected: Option<&str>,
+                         config: &FederationConfig) -> Result<(String, LinkKey, LinkKey), io::Error>
+    where R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin {
+
+    let nonce = nonce()?;
+    write_line(writer, &json::object!{
+        "type" => "hello", "server" => config.server_name.as_str(), "nonce" => nonce.as_str(),
+    }).await?;
+
+    let hello = read_line(reader).await?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "closed during handshake"))?;
+    let (server, peer_nonce) = match (hello["type"].as_str(), hello["server"].as_str(), hello["nonce"].as_str()) {
+        (Some("hello"), Some(server), Some(nonce)) => (server.to_string(), nonce.to_string()),
+        _ => return Err(invalid("expected hello")),
+    };
+    if let Some(name) = expected {
+        if name != server {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                      format!("expected server {}, got {}", name, server)));
+        }
+    }
+    let peer = config.peers.iter().find(|p| p.name == server)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied,
+                                      format!("unknown server {}", server)))?;
+
+    let own = (config.server_name.as_str(), nonce.as_str());
+    let other = (server.as_str(), peer_nonce.as_str());
+    let mac = auth_mac(&peer.secret, own, other).finalize().into_bytes();
+    write_line(writer, &json::object!{ "type" => "auth", "mac" => to_hex(&mac) }).await?;
+
+    let auth = read_line(reader).await?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "closed during handshake"))?;
+    let mac = match (auth["type"].as_str(), auth["mac"].as_str().and_then(from_hex)) {
+        (Some("auth"), Some(mac)) => mac,
+        (Some("close"), _) => return Err(io::Error::new(io::ErrorKind::ConnectionAborted,
+                                                        format!("refused: {}", auth["reason"]))),
+        _ => return Err(invalid("expected auth")),
+    };
+    if auth_mac(&peer.secret, other, own).verify(&mac).is_err() {
+        let close = json::object!{ "type" => "close", "reason" => "authentication failed" };
+        let _ = write_line(writer, &close).await;
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                  format!("server {} failed to authenticate", server)));
+    }
+
+    let key = link_key(&peer.secret, own, other);
+    let signer = LinkKey::new(&key, &config.server_name);
+    let verifier = LinkKey::new(&key, &server);
+    Ok((server, signer, verifier))
+}
+
+/// HMAC-SHA256 of the given parts, separated by newlines
+fn hmac(key: &[u8], parts: &[&str]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts any key length");
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            mac.update(b"\n");
+        }
+        mac.update(part.as_bytes());
+    }
+    mac
+}
+
+/// Proof that the `signer` server, as (name, nonce), knows the secret shared with `verifier`
+fn auth_mac(secret: &str, signer: (&str, &str), verifier: (&str, &str)) -> Hmac<Sha256> {
+    hmac(secret.as_bytes(), &["auth", signer.0, signer.1, verifier.0, verifier.1])
+}
+
+/// Key of a link between two servers, as (name, nonce), derived from their shared secret. Both
+/// servers derive the same key, whatever the order they are given in.
+fn link_key(secret: &str, a: (&str, &str), b: (&str, &str)) -> Vec<u8> {
+    let (first, second) = if a.0 < b.0 { (a, b) } else { (b, a) };
+    hmac(secret.as_bytes(), &["link", first.0, first.1, second.0, second.1])
+        .finalize().into_bytes().to_vec()
+}
+
+/// Signs or verifies the lines one server sends on a link. Every line starts with an HMAC of
+/// the sending server, the number of lines it sent before and the line, keyed with the key of
+/// the link. Whoever relays a link cannot inject, alter, replay or reorder lines.
+struct LinkKey {
+    key: Vec<u8>,
+    sender: String,
+    count: u64, // Lines signed or verified so far
+}
+
+impl LinkKey {
+
+    fn new(key: &[u8], sender: &str) -> LinkKey {
+        LinkKey { key: key.to_vec(), sender: sender.to_string(), count: 0 }
+    }
+
+    fn mac(&self, line: &str) -> Hmac<Sha256> {
+        hmac(&self.key, &[&self.sender, &self.count.to_string(), line])
+    }
+
+    /// Returns the line to send, with its HMAC and the line break
+    fn sign(&mut self, line: &str) -> String {
+        let mac = to_hex(&self.mac(line).finalize().into_bytes());
+        self.count += 1;
+        format!("{} {}\n", mac, line)
+    }
+
+    /// Checks the HMAC of a received line, returning the line without it
+    fn verify<'a>(&mut self, signed: &'a str) -> Result<&'a str, io::Error> {
+        let (mac, line) = match signed.find(' ') {
+            Some(i) => (from_hex(&signed[..i]), signed[i + 1..].trim_end_matches('\n')),
+            None => (None, signed),
+        };
+        match mac {
+            Some(mac) if self.mac(line).verify(&mac).is_ok() => {
+                self.count += 1;
+                Ok(line)
+            },
+            _ => Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                    format!("line {} from {} has an invalid signature", self.count, self.sender))),
+        }
+    }
+}
+
+/// Returns 16 random bytes, hex encoded
+fn nonce() -> Result<String, io::Error> {
+    let mut bytes = [0u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(to_hex(&bytes))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes a hex string, None if it is not valid (including odd lengths)
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+async fn write_line<W: AsyncWrite + Unpin>(writer: &mut W, msg: &JsonValue) -> Result<(), io::Error> {
+    writer.write_all(format!("{}\n", msg.dump()).as_bytes()).await
+}
+
+/// Reads a line, None when the link is closed
+async fn read_raw_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<String>, io::Error> {
+    let mut line = Vec::new();
+    let n = (&mut *reader).take(MAX_LINE).read_until(b'\n', &mut line).await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(invalid("line too long"));
+    }
+    String::from_utf8(line).map(Some).map_err(|_| invalid("not UTF-8"))
+}
+
+/// Reads a message of the handshake, None when the link is closed
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<JsonValue>, io::Error> {
+    match read_raw_line(reader).await? {
+        Some(line) => json::parse(&line).map(Some).map_err(|e| invalid(&e.to_string())),
+        None => Ok(None),
+    }
+}
+
+/// Handles the messages of a linked server until the link is closed or silent for `timeout`
+async fn read_link<R: AsyncBufRead + Unpin>(reader: &mut R,
+                                            server: &str,
+                                            id: u64,
+                                            mut verifier: LinkKey,
+                                            shared_conn: &Mutex<SharedConn>,
+                                            timeout: Duration) -> Result<(), io::Error> {
+    loop {
+        let line = match time::timeout(timeout, read_raw_line(reader)).await {
+            Ok(Ok(Some(line))) => line,
+            Ok(Ok(None)) => return Ok(()),
+            Ok(Err(err)) => return Err(err),
+            Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "link timeout")),
+        };
+        let line = verifier.verify(&line)?;
+        let msg = json::parse(line).map_err(|e| invalid(&e.to_string()))?;
+        let mut conn = shared_conn.lock().await;
+        if !conn.is_current_link(server, id) {
+            return Ok(()); // Replaced by another connection
+        }
+        conn.link_message(server, msg)?;
+    }
+}
+
+/// Writes the queued messages to a linked server, pinging it when there is nothing to send
+async fn write_link<W: AsyncWrite + Unpin>(mut writer: W,
+                                           mut rx: mpsc::UnboundedReceiver<String>,
+                                           mut signer: LinkKey,
+                                           ping_interval: Duration) {
+    let ping = json::object!{ "type" => "ping" }.dump();
+    loop {
+        let line = match time::timeout(ping_interval, rx.recv()).await {
+            Ok(Some(line)) => line,
+            Ok(None) => break, // The link was removed
+            Err(_) => ping.clone(),
+        };
+        if let Err(err) = writer.write_all(signer.sign(&line).as_bytes()).await {
+            debug!("Cannot write to server link: {}", err);
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Outgoing, Rx};
+    use crate::archive::Archive;
+    use crate::backplane::Cluster;
+    use crate::config::PeerConfig;
+
+    fn server(name: &str) -> SharedConn {
+        SharedConn::new(Archive::disabled(), Federation::new(name), Cluster::disabled())
+    }
+
+    fn login(conn: &mut SharedConn, name: &str) -> Rx {
+        let (tx, rx) = mpsc::unbounded_channel();
+        conn.add(name.to_string(), 1, tx, 1).unwrap();
+        rx
+    }
+
+    /// Links a server, returning the link id and the lines queued to it after the burst
+    fn link(conn: &mut SharedConn, server: &str, outgoing: bool) -> (u64, mpsc::UnboundedReceiver<String>) {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let id = conn.link_up(server, tx, outgoing).unwrap();
+        sent(&mut rx);
+        (id, rx)
+    }
+
+    /// Lines queued to a linked server
+    fn sent(rx: &mut mpsc::UnboundedReceiver<String>) -> Vec<JsonValue> {
+        let mut lines = Vec::new();
+        while let Ok(line) = rx.try_recv() {
+            lines.push(json::parse(&line).unwrap());
+        }
+        lines
+    }
+
+    /// Commands queued to a session
+    fn received(rx: &mut Rx) -> Vec<Command> {
+        let mut commands = Vec::new();
+        while let Ok(outgoing) = rx.try_recv() {
+            if let Outgoing::Command(command, _) = outgoing {
+                commands.push(command);
+            }
+        }
+        commands
+    }
+
+    fn msg(sender: &str, target: &str, text: &str) -> Command {
+        Command::Msg(sender.to_string(), target.to_string(), text.to_string())
+    }
+
+    fn listusr(group: &str, operation: ListUsrOperation, user: &str) -> Command {
+        Command::ListUsr(group.to_string(), operation, format!("\n{}", user))
+    }
+
+    #[test]
+    fn link_keys_reject_altered_and_replayed_lines() {
+        let key = link_key("secret", ("a", "n1"), ("b", "n2"));
+        assert_eq!(key, link_key("secret", ("b", "n2"), ("a", "n1")));
+        assert_ne!(key, link_key("secret", ("a", "n1"), ("b", "n3")));
+        assert_ne!(key, link_key("other", ("a", "n1"), ("b", "n2")));
+
+        let mut signer = LinkKey::new(&key, "a");
+        let first = signer.sign("{\"type\":\"ping\"}");
+        let second = signer.sign("{\"type\":\"ping\"}");
+        assert_ne!(first, second);
+
+        let mut verifier = LinkKey::new(&key, "a");
+        assert_eq!(verifier.verify(&first).unwrap(), "{\"type\":\"ping\"}");
+        assert!(verifier.verify(&first).is_err()); // Replayed
+        assert!(LinkKey::new(&key, "b").verify(&first).is_err()); // Reflected to its sender
+        assert!(LinkKey::new(&key, "a").verify(&first.replace("ping", "pong")).is_err());
+        assert!(LinkKey::new(&key, "a").verify("{\"type\":\"ping\"}\n").is_err());
+
+        // Proofs of the handshake are bound to the roles of both servers
+        let a = auth_mac("secret", ("a", "n1"), ("b", "n2")).finalize().into_bytes();
+        assert!(auth_mac("secret", ("a", "n1"), ("b", "n2")).verify(&a).is_ok());
+        assert!(auth_mac("secret", ("b", "n2"), ("a", "n1")).verify(&a).is_err());
+        assert!(auth_mac("secret", ("a", "n1"), ("c", "n2")).verify(&a).is_err());
+    }
+
+    #[test]
+    fn announced_servers_are_routed_and_loops_refused() {
+        let mut a = server("a");
+        let (_, mut to_b) = link(&mut a, "b", true);
+        let (_, mut to_d) = link(&mut a, "d", false);
+        assert_eq!(sent(&mut to_b), vec![json::object!{ "type" => "server", "name" => "d", "path" => json::array!["a"] }]);
+
+        a.link_message("b", json::object!{ "type" => "server", "name" => "c", "path" => json::array!["b"] }).unwrap();
+        assert!(a.federation.is_reachable("c"));
+        assert_eq!(a.federation.servers(), vec![("b".to_string(), "b".to_string()),
+                                                ("c".to_string(), "b".to_string()),
+                                                ("d".to_string(), "d".to_string())]);
+        assert_eq!(sent(&mut to_d)[0]["name"], "c");
+        assert!(sent(&mut to_b).is_empty());
+
+        // c reached through d too would form a loop
+        let err = a.link_message("d", json::object!{ "type" => "server", "name" => "c" }).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        let (tx, _rx) = mpsc::unbounded_channel();
+        assert!(a.link_up("c", tx, true).is_err());
+        let (tx, _rx) = mpsc::unbounded_channel();
+        assert!(a.link_up("a", tx, false).is_err());
+
+        // Messages that went through this server already are dropped
+        a.link_message("b", json::object!{ "type" => "server", "name" => "e", "path" => json::array!["b", "a"] }).unwrap();
+        assert!(!a.federation.is_reachable("e"));
+    }
+
+    #[test]
+    fn connection_opened_by_the_lowest_name_is_kept() {
+        // b receives the connection of a first, then its own connection to a completes
+        let mut b = server("b");
+        let (first, _) = link(&mut b, "a", false);
+        let (tx, _rx) = mpsc::unbounded_channel();
+        assert!(b.link_up("a", tx, true).is_err());
+        assert!(b.is_current_link("a", first));
+
+        // Its own connection completes first, and is replaced
+        let mut b = server("b");
+        let (first, mut old) = link(&mut b, "a", true);
+        let (second, _new) = link(&mut b, "a", false);
+        assert_ne!(first, second);
+        assert!(b.is_current_link("a", second));
+        assert_eq!(sent(&mut old)[0]["type"], "close");
+
+        // The replaced link ending does not take the new one down
+        b.link_down("a", first);
+        assert!(b.federation.is_reachable("a"));
+        b.link_down("a", second);
+        assert!(!b.federation.is_reachable("a"));
+
+        // a reconnecting before the previous connection timed out replaces it
+        let (first, _) = link(&mut b, "a", false);
+        let (second, _) = link(&mut b, "a", false);
+        assert!(!b.is_current_link("a", first));
+        assert!(b.is_current_link("a", second));
+    }
+
+    #[tokio::test]
+    async fn netsplit_forgets_servers_and_members_behind_the_link() {
+        let mut a = server("a");
+        let mut alice = login(&mut a, "alice");
+        a.join_group("#g", "alice").await.unwrap();
+        let (to_b, _) = link(&mut a, "b", true);
+        let (_, mut to_d) = link(&mut a, "d", true);
+
+        a.link_message("b", json::object!{ "type" => "server", "name" => "c" }).unwrap();
+        a.link_message("b", json::object!{ "type" => "join", "group" => "#g", "user" => "carol@c" }).unwrap();
+        a.link_message("d", json::object!{ "type" => "join", "group" => "#g", "user" => "dave@d" }).unwrap();
+        assert_eq!(a.federation.remote_members("#g"), &["carol@c".to_string(), "dave@d".to_string()]);
+        received(&mut alice);
+        sent(&mut to_d);
+
+        a.link_down("b", to_b);
+        assert_eq!(a.federation.servers(), vec![("d".to_string(), "d".to_string())]);
+        assert_eq!(a.federation.remote_members("#g"), &["dave@d".to_string()]);
+        assert_eq!(received(&mut alice), vec![listusr("#g", ListUsrOperation::Remove, "carol@c")]);
+        let mut squits: Vec<String> = sent(&mut to_d).iter()
+            .filter(|line| line["type"] == "squit")
+            .map(|line| line["name"].to_string())
+            .collect();
+        squits.sort();
+        assert_eq!(squits, vec!["b", "c"]);
+
+        // Servers lost behind a linked server
+        a.link_message("d", json::object!{ "type" => "server", "name" => "e" }).unwrap();
+        a.link_message("d", json::object!{ "type" => "squit", "name" => "e" }).unwrap();
+        assert!(!a.federation.is_reachable("e"));
+        assert!(a.federation.is_reachable("d"));
+    }
+
+    #[tokio::test]
+    async fn messages_of_servers_not_behind_the_link_are_ignored() {
+        let mut a = server("a");
+        let mut alice = login(&mut a, "alice");
+        a.join_group("#g", "alice").await.unwrap();
+        link(&mut a, "b", true);
+        link(&mut a, "d", true);
+
+        a.link_message("d", msg_line("msg", "mallory@b", "alice@a")).unwrap();
+        a.link_message("d", msg_line("group", "mallory@b", "#g")).unwrap();
+        a.link_message("d", json::object!{ "type" => "join", "group" => "#g", "user" => "mallory@b" }).unwrap();
+        assert!(received(&mut alice).is_empty());
+        assert!(a.federation.remote_members("#g").is_empty());
+
+        a.link_message("b", msg_line("msg", "bob@b", "alice@a")).unwrap();
+        a.link_message("b", msg_line("group", "bob@b", "#g")).unwrap();
+        assert_eq!(received(&mut alice), vec![msg("bob@b", "alice", "hi"), msg("bob@b", "#g", "hi")]);
+    }
+
+    #[tokio::test]
+    async fn closed_groups_are_left_on_linked_servers() {
+        let mut a = server("a");
+        let mut alice = login(&mut a, "alice");
+        a.join_group("#g", "alice").await.unwrap();
+        let (_, mut to_b) = link(&mut a, "b", true);
+        a.link_message("b", json::object!{ "type" => "join", "group" => "#g", "user" => "bob@b" }).unwrap();
+        received(&mut alice);
+
+        assert_eq!(a.close_group("#g", "spam").unwrap(), 1);
+        assert_eq!(sent(&mut to_b), vec![json::object!{
+            "type" => "leave", "group" => "#g", "user" => "alice@a", "path" => json::array!["a"],
+        }]);
+        assert_eq!(received(&mut alice).len(), 1);
+    }
+
+    fn msg_line(kind: &str, sender: &str, target: &str) -> JsonValue {
+        let mut line = json::object!{ "type" => kind, "sender" => sender, "text" => "hi" };
+        line[if kind == "group" { "group" } else { "target" }] = target.into();
+        line
+    }
+
+    fn config(name: &str, address: &str, peer: &str, peer_address: &str, secret: &str) -> FederationConfig {
+        FederationConfig {
+            server_name: name.to_string(),
+            address: address.to_string(),
+            ping_interval: 30,
+            reconnect_interval: 1,
+            peers: vec![PeerConfig {
+                name: peer.to_string(),
+                address: peer_address.to_string(),
+                secret: secret.to_string(),
+            }],
+        }
+    }
+
+    /// Starts two servers on the loopback interface, b connecting to a
+    async fn start_pair(secret_a: &str, secret_b: &str) -> (Arc<Mutex<SharedConn>>, Arc<Mutex<SharedConn>>) {
+        let a = Arc::new(Mutex::new(server("a")));
+        let b = Arc::new(Mutex::new(server("b")));
+        let audit = Arc::new(AuditLog::disabled());
+        let addr = start(config("a", "127.0.0.1:0", "b", "", secret_a), Arc::clone(&a), Arc::clone(&audit))
+            .await.unwrap().unwrap();
+        start(config("b", "", "a", &addr.to_string(), secret_b), Arc::clone(&b), audit).await.unwrap();
+        (a, b)
+    }
+
+    /// Waits up to 5 seconds for a condition on a server
+    async fn wait_for<F: Fn(&SharedConn) -> bool>(conn: &Mutex<SharedConn>, condition: F) -> bool {
+        for _ in 0..500 {
+            if condition(&*conn.lock().await) {
+                return true;
+            }
+            time::delay_for(Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    /// Waits up to 5 seconds for commands to be queued to a session
+    async fn wait_received(rx: &mut Rx) -> Vec<Command> {
+        for _ in 0..500 {
+            let commands = received(rx);
+            if !commands.is_empty() {
+                return commands;
+            }
+            time::delay_for(Duration::from_millis(10)).await;
+        }
+        Vec::new()
+    }
+
+    #[tokio::test]
+    async fn linked_servers_relay_direct_and_group_messages() {
+        let (a, b) = start_pair("secret", "secret").await;
+        assert!(wait_for(&a, |a| a.federation.is_reachable("b")).await);
+        assert!(wait_for(&b, |b| b.federation.is_reachable("a")).await);
+
+        let mut alice = login(&mut *a.lock().await, "alice");
+        let mut bob = login(&mut *b.lock().await, "bob");
+        a.lock().await.send("alice", msg("alice", "bob@b", "hi bob")).await.unwrap();
+        assert_eq!(wait_received(&mut bob).await, vec![msg("alice@a", "bob", "hi bob")]);
+        b.lock().await.send("bob", msg("bob", "alice@a", "hi alice")).await.unwrap();
+        assert_eq!(wait_received(&mut alice).await, vec![msg("bob@b", "alice", "hi alice")]);
+
+        b.lock().await.join_group("#g", "bob").await.unwrap();
+        assert!(wait_for(&a, |a| a.federation.remote_members("#g") == ["bob@b".to_string()]).await);
+        a.lock().await.join_group("#g", "alice").await.unwrap();
+        assert_eq!(wait_received(&mut bob).await, vec![listusr("#g", ListUsrOperation::Add, "alice@a")]);
+        a.lock().await.send("alice", msg("alice", "#g", "hi all")).await.unwrap();
+        assert_eq!(wait_received(&mut bob).await, vec![msg("alice@a", "#g", "hi all")]);
+    }
+
+    #[tokio::test]
+    async fn servers_with_another_secret_are_not_linked() {
+        let (a, b) = start_pair("secret", "guess").await;
+        time::delay_for(Duration::from_millis(300)).await;
+        assert!(!a.lock().await.federation.is_reachable("b"));
+        assert!(!b.lock().await.federation.is_reachable("a"));
+    }
+}
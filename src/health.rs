@@ -0,0 +1,86 @@
+use tokio::sync::Mutex;
+use tokio::time::{self, Duration, Instant};
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::SharedConn;
+
+/// How often the lock watchdog tries to take the `SharedConn` lock
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+/// The server is unhealthy if the `SharedConn` lock could not be taken for this long
+const MAX_LOCK_STALL: Duration = Duration::from_secs(10);
+
+/// Health state of the server, reported by the `/healthz` and `/readyz` HTTP endpoints
+pub struct Health {
+    started: Instant,
+    pub listening: AtomicBool,       // The client listener is accepting connections
+    pub database_loaded: AtomicBool,
+    pub ready: AtomicBool,           // Turned off when the server starts shutting down
+    last_lock_ok: AtomicU64,         // Milliseconds since `started` of the last taken lock
+    last_lock_wait: AtomicU64,       // Microseconds waited for the last taken lock
+}
+
+impl Health {
+
+    pub fn new() -> Health {
+        Health {
+            started: Instant::now(),
+            listening: AtomicBool::new(false),
+            database_loaded: AtomicBool::new(false),
+            ready: AtomicBool::new(false),
+            last_lock_ok: AtomicU64::new(0),
+            last_lock_wait: AtomicU64::new(0),
+        }
+    }
+
+    /// Time since the `SharedConn` lock was last taken by the watchdog
+    pub fn lock_stall(&self) -> Duration {
+        let last_ok = Duration::from_millis(self.last_lock_ok.load(Ordering::SeqCst));
+        self.started.elapsed().checked_sub(last_ok).unwrap_or_default()
+    }
+
+    /// Returns true if the server is alive and able to serve users
+    pub fn is_healthy(&self) -> bool {
+        self.listening.load(Ordering::SeqCst)
+            && self.database_loaded.load(Ordering::SeqCst)
+            && self.lock_stall() < MAX_LOCK_STALL
+    }
+
+    /// Returns true if the server is healthy and accepting new users
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst) && self.is_healthy()
+    }
+
+    /// Describes the state as a JSON object
+    pub fn report(&self) -> String {
+        json::object!{
+            "healthy" => self.is_healthy(),
+            "ready" => self.is_ready(),
+            "listening" => self.listening.load(Ordering::SeqCst),
+            "database_loaded" => self.database_loaded.load(Ordering::SeqCst),
+            "lock_watchdog" => json::object!{
+                "stall_ms" => self.lock_stall().as_millis() as u64,
+                "last_wait_us" => self.last_lock_wait.load(Ordering::SeqCst),
+            },
+        }.dump()
+    }
+}
+
+/// Periodically takes the `SharedConn` lock to detect deadlocks or heavy contention
+pub async fn watchdog(health: Arc<Health>, shared_conn: Arc<Mutex<SharedConn>>) {
+    let mut interval = time::interval(PROBE_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let start = Instant::now();
+        match time::timeout(MAX_LOCK_STALL, shared_conn.lock()).await {
+            Ok(_guard) => {
+                let now = health.started.elapsed().as_millis() as u64;
+                health.last_lock_ok.store(now, Ordering::SeqCst);
+                health.last_lock_wait.store(start.elapsed().as_micros() as u64, Ordering::SeqCst);
+            },
+            Err(_) => warn!("SharedConn lock not available after {:?}", MAX_LOCK_STALL),
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{self, Duration};
+
+use std::io;
+use std::sync::Arc;
+
+use crate::admin::AdminContext;
+use crate::metrics;
+
+/// Max size of the request head, the rest is ignored
+const MAX_HEAD: usize = 8 * 1024;
+
+/// Serves the HTTP monitoring endpoint at the given address:
+/// * `GET /metrics` returns the server metrics in the Prometheus text format.
+/// * `GET /healthz` returns 200 if the server is alive, 503 otherwise.
+/// * `GET /readyz` returns 200 if the server accepts new users, 503 otherwise
+///   (e.g. while shutting down).
+pub async fn serve(addr: &str, ctx: Arc<AdminContext>) -> Result<(), io::Error> {
+    let mut listener = TcpListener::bind(addr).await?;
+    info!("HTTP endpoint listening on {}", addr);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let ctx = Arc::clone(&ctx);
+        tokio::spawn(async move {
+            if let Err(err) = handle(stream, ctx).await {
+                debug!("HTTP request from {} failed: {}", peer, err);
+            }
+        });
+    }
+}
+
+async fn handle(mut stream: TcpStream, ctx: Arc<AdminContext>) -> Result<(), io::Error> {
+    // Read the request head, only the request line is used
+    let mut head = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_HEAD {
+        let n = time::timeout(Duration::from_secs(5), stream.read(&mut chunk)).await??;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&chunk[..n]);
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.lines().next().unwrap_or("").split_whitespace();
+    let method = request_line.next().unwrap_or("");
+    let path = request_line.next().unwrap_or("");
+
+    let (status, content_type, body) = route(method, path, &ctx).await;
+    let response = format!("HTTP/1.1 {}\r\n\
+                            Content-Type: {}\r\n\
+                            Content-Length: {}\r\n\
+                            Connection: close\r\n\r\n{}",
+                           status, content_type, body.len(), body);
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown(std::net::Shutdown::Write)
+}
+
+const TEXT: &str = "text/plain; charset=utf-8";
+const PROMETHEUS: &str = "text/plain; version=0.0.4";
+const JSON: &str = "application/json";
+
+/// Returns the status line, the content type and the body of the response
+async fn route(method: &str, path: &str, ctx: &AdminContext) -> (&'static str, &'static str, String) {
+    if method != "GET" {
+        return ("405 Method Not Allowed", TEXT, "method not allowed\n".to_string());
+    }
+    let status = |ok| if ok { "200 OK" } else { "503 Service Unavailable" };
+    match path {
+        "/metrics" => ("200 OK", PROMETHEUS, metrics::render(ctx).await),
+        "/healthz" => (status(ctx.health.is_healthy()), JSON, ctx.health.report()),
+        "/readyz" => (status(ctx.health.is_ready()), JSON, ctx.health.report()),
+        _ => ("404 Not Found", TEXT, "not found\n".to_string()),
+    }
+}
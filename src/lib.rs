@@ -2,54 +2,341 @@ use ostrich_core::*;
 
 #[macro_use] extern crate log;
 use tokio::sync::mpsc;
-use tokio::net::{TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt, AsyncRead};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, AsyncRead, AsyncWrite};
 use tokio::stream::{Stream};
+use tokio::time::{self, Duration, Instant, Interval};
 
 use std::collections::HashMap;
 use std::io::{self, BufReader, prelude::*};
+use std::net::IpAddr;
 use std::fs::File;
 
 use core::task::{Poll, Context};
 use core::pin::Pin;
 
+use metrics::{Metrics, METRICS};
+use archive::Archive;
+use backplane::Cluster;
+use federation::Federation;
+
+pub mod admin;
+pub mod archive;
+pub mod audit;
+pub mod backplane;
+pub mod ban;
 pub mod config;
+pub mod federation;
+pub mod health;
+pub mod http;
+pub mod listener;
+pub mod logfile;
+pub mod logging;
+pub mod metrics;
+pub mod proxy;
+pub mod reload;
+pub mod systemd;
+pub mod websocket;
+
+pub type Tx = mpsc::UnboundedSender<Outgoing>;
+pub type Rx = mpsc::UnboundedReceiver<Outgoing>;
+
+/// Name used as sender in the messages generated by the server itself.
+/// Usernames starting with '!' are reserved and cannot be used to log in.
+pub const SERVER_NAME: &str = "!server";
+
+/// Data queued to a connected user through its Tx
+pub enum Outgoing {
+    Command(Command, Instant), // A command to write to the user's socket, time when queued
+    Close(String),             // Send the reason to the user and close the session
+}
 
-pub type Tx = mpsc::UnboundedSender<Command>;
-pub type Rx = mpsc::UnboundedReceiver<Command>;
+/// Queues a command to be sent by the session owning the Tx
+fn queue(tx: &Tx, command: Command) -> Result<(), mpsc::error::SendError<Outgoing>> {
+    tx.send(Outgoing::Command(command, Instant::now()))?;
+    METRICS.queued();
+    Ok(())
+}
+
+/// Identifies a session (a connection) of a user, users may have several at the same time
+pub type SessionId = u64;
 
 pub struct SharedConn {
-    shared_conn: HashMap<String, Tx>,       // Username, Tx
+    shared_conn: HashMap<String, Vec<(SessionId, Tx)>>, // Username, sessions of the user
     groups: HashMap<String, Vec<String>>,   // Group name, List of usernames
+    addresses: HashMap<SessionId, IpAddr>,  // IP address of the sessions, if any
+    closing: bool,                          // No new users are accepted when true
+    archive: Archive,                       // Transcripts of group conversations
+    federation: Federation,                 // Links to other servers
+    cluster: Cluster,                       // Users and groups of the other instances
 }
 
 impl SharedConn {
 
-    pub fn new() -> SharedConn{
-        SharedConn{ shared_conn: HashMap::new(), groups: HashMap::new() }
+    pub fn new(archive: Archive, federation: Federation, cluster: Cluster) -> SharedConn{
+        SharedConn{ shared_conn: HashMap::new(), groups: HashMap::new(), addresses: HashMap::new(),
+                    closing: false, archive, federation, cluster }
+    }
+
+    pub fn federation(&self) -> &Federation {
+        &self.federation
+    }
+
+    pub fn cluster(&self) -> &Cluster {
+        &self.cluster
     }
 
-    pub fn add(&mut self, name: String, tx: Tx) -> Result<(), io::Error> {
-        if self.shared_conn.contains_key(&name) {
+    /// Adds a session of a user. A user may have up to `max_sessions` sessions at the same
+    /// time (0 for no limit), counting its sessions on the other instances of the cluster.
+    pub fn add(&mut self, name: String, session: SessionId, tx: Tx,
+               max_sessions: usize) -> Result<(), io::Error> {
+        if self.closing {
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused,
+                                      "The server is shutting down"));
+        }
+        let count = self.session_count(&name);
+        let total = count + self.cluster.remote_sessions(&name);
+        if max_sessions == 1 && total > 0 {
             return Err(io::Error::new(io::ErrorKind::AlreadyExists,
                                       "A user with the same credentials is already loged in"));
         }
-        self.shared_conn.insert(name, tx);
+        if max_sessions > 0 && total >= max_sessions {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                      format!("User {} already has {} sessions, the maximum allowed",
+                                              name, total)));
+        }
+        self.cluster.set_sessions(&name, count + 1);
+        self.shared_conn.entry(name).or_default().push((session, tx));
+        Ok(())
+    }
+
+    /// Removes a session of a user. When the last session of the user is removed, the user
+    /// leaves all its groups, unless it still has sessions on other instances of the cluster.
+    pub async fn remove(&mut self, name: &str, session: SessionId) -> Result<(), io::Error> {
+        let sessions = match self.shared_conn.get_mut(name) {
+            Some(sessions) => sessions,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, 
+                                              "User not found")),
+        };
+        // The session may have been taken over already
+        match sessions.iter().position(|(id, _)| *id == session) {
+            Some(index) => sessions.remove(index),
+            None => return Err(io::Error::new(io::ErrorKind::NotFound,
+                                              "Session not found")),
+        };
+        self.addresses.remove(&session);
+        let count = sessions.len();
+        self.cluster.set_sessions(name, count);
+        if count > 0 {
+            return Ok(());
+        }
+        self.shared_conn.remove(name);
+        if self.cluster.is_online(name) {
+            trace!("User {} keeps its groups for its sessions on other instances", name);
+            return Ok(());
+        }
+
+        let groups: Vec<String> = self.groups.iter()
+            .filter(|(_, users)| users.iter().any(|u| u == name))
+            .map(|(group, _)| group.clone())
+            .collect();
+        for group in groups {
+            if let Err(err) = self.leave(name, &group) {
+                warn!("Could not remove user {} from group {}: {}", name, group, err);
+            } else {
+                trace!("Left group {}", group);
+            }
+        }
         Ok(())
     }
 
-    pub fn remove(&mut self, name: &str) -> Result<(), io::Error> {
-        match self.shared_conn.remove(name) {
-            Some(_) => Ok(()),
-            None => Err(io::Error::new(io::ErrorKind::NotFound, 
-                                       "User not found")),
+    /// Removes from their groups the members that no longer have a session on any instance,
+    /// e.g. once the sessions of a user on other instances are closed
+    fn leave_gone_users(&mut self) {
+        let mut gone = Vec::new();
+        for (group, users) in &self.groups {
+            for user in users {
+                if !self.shared_conn.contains_key(user) && !self.cluster.is_online(user) {
+                    gone.push((group.clone(), user.clone()));
+                }
+            }
         }
+        for (group, user) in gone {
+            if let Err(err) = self.leave(&user, &group) {
+                warn!("Could not remove user {} from group {}: {}", user, group, err);
+            }
+        }
+    }
+
+    /// Closes the oldest session of a user to make room for a new one, e.g. when a client that
+    /// crashed logs in again before its old session timed out. The session is sent the given
+    /// reason and removed right away. Returns its id. Sessions on other instances of the
+    /// cluster cannot be taken over.
+    pub async fn take_over(&mut self, name: &str, reason: &str) -> Result<SessionId, io::Error> {
+        let oldest = self.shared_conn.get(name).and_then(|sessions| sessions.first()).cloned();
+        let (session, tx) = match oldest {
+            Some(oldest) => oldest,
+            None if self.cluster.is_online(name) => return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                format!("User {} is logged in to another instance, its session cannot be taken over", name))),
+            None => return Err(io::Error::new(io::ErrorKind::NotFound,
+                                              format!("User {} has no session to take over", name))),
+        };
+        if tx.send(Outgoing::Close(reason.to_string())).is_err() {
+            debug!("Cannot notify {} about the take over, session already gone", name);
+        }
+        if let Err(err) = self.remove(name, session).await {
+            warn!("Could not remove session {} of {}: {}", session, name, err);
+        }
+        Ok(session)
+    }
+
+    /// Returns the number of sessions of a user on this server
+    pub fn session_count(&self, name: &str) -> usize {
+        self.shared_conn.get(name).map_or(0, |sessions| sessions.len())
+    }
+
+    /// Queues a command to every session of a user. Returns false if no session could take it.
+    fn deliver(&self, name: &str, command: Command) -> bool {
+        let mut delivered = false;
+        for (_, tx) in self.shared_conn.get(name).into_iter().flatten() {
+            delivered |= queue(tx, command.clone()).is_ok();
+        }
+        delivered
+    }
+
+    /// Queues a command to every session of the members of a group, except `sender`. Members
+    /// that joined from other instances of the cluster may have sessions here too.
+    fn deliver_to_group(&self, group: &str, command: &Command, sender: &str) {
+        let local = self.groups.get(group).map_or(&[][..], |users| users.as_slice());
+        let remote = self.cluster.remote_members(group);
+        for name in local.iter().chain(remote.iter().filter(|name| !local.contains(name))) {
+            if name != sender {
+                self.deliver(name, command.clone());
+            }
+        }
+    }
+
+    /// Notifies the members of a group that a user of another server or instance joined or
+    /// left it
+    fn notify_group(&self, group: &str, operation: ListUsrOperation, user: &str) {
+        let notification = Command::ListUsr(group.to_string(), operation, format!("\n{}", user));
+        self.deliver_to_group(group, &notification, user);
+    }
+
+    /// Asks every connected user to close its session, sending them the given reason.
+    /// Once called, new users are not allowed to log in.
+    pub fn close_all(&mut self, reason: &str) {
+        self.closing = true;
+        for (name, sessions) in self.shared_conn.iter() {
+            for (_, tx) in sessions {
+                if tx.send(Outgoing::Close(reason.to_string())).is_err() {
+                    debug!("Cannot notify {} about closing, session already gone", name);
+                }
+            }
+        }
+    }
+
+    /// Asks every session of the given user to close, sending them the given reason
+    pub fn close(&mut self, name: &str, reason: &str) -> Result<(), io::Error> {
+        let sessions = match self.shared_conn.get(name) {
+            Some(sessions) => sessions,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound,
+                                              format!("User {} not connected", name))),
+        };
+        let mut closed = false;
+        for (_, tx) in sessions {
+            closed |= tx.send(Outgoing::Close(reason.to_string())).is_ok();
+        }
+        if !closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe,
+                                      format!("Session of {} already closed", name)));
+        }
+        Ok(())
+    }
+
+    /// Records the IP address a session connected from
+    pub fn set_address(&mut self, session: SessionId, ip: IpAddr) {
+        self.addresses.insert(session, ip);
+    }
+
+    /// Asks the sessions connected from the addresses accepted by `covered` to close, sending
+    /// them the given reason. Returns the number of sessions asked to close.
+    pub fn close_addresses<F: Fn(IpAddr) -> bool>(&self, covered: F, reason: &str) -> usize {
+        let mut count = 0;
+        for (name, sessions) in &self.shared_conn {
+            for (session, tx) in sessions {
+                if !matches!(self.addresses.get(session), Some(ip) if covered(*ip)) {
+                    continue;
+                }
+                if tx.send(Outgoing::Close(reason.to_string())).is_ok() {
+                    count += 1;
+                } else {
+                    debug!("Cannot close session {} of {}, already gone", session, name);
+                }
+            }
+        }
+        count
+    }
+
+    /// Sends a message from the server to every connected user.
+    /// Returns the number of users the message was queued to.
+    pub fn broadcast(&mut self, text: &str) -> usize {
+        let mut count = 0;
+        for name in self.shared_conn.keys() {
+            let command = Command::Msg(SERVER_NAME.to_string(), name.clone(), text.to_string());
+            if self.deliver(name, command) {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Removes a group, notifying its members with the given reason. Linked servers and the
+    /// other instances of the cluster are told that the members left, so they stop routing
+    /// messages of the group here.
+    /// Returns the number of members the group had.
+    pub fn close_group(&mut self, group_name: &str, reason: &str) -> Result<usize, io::Error> {
+        let members = match self.groups.remove(group_name) {
+            Some(m) => m,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound,
+                                              format!("Group {} not found", group_name))),
+        };
+        for name in &members {
+            let notice = Command::Msg(SERVER_NAME.to_string(), name.clone(),
+                                      format!("Group {} closed: {}", group_name, reason));
+            self.deliver(name, notice);
+            self.federation.left(group_name, name);
+            self.cluster.left(group_name, name);
+        }
+        self.archive.close(group_name);
+        Ok(members.len())
+    }
+
+    /// Writes the transcript lines still queued and stops archiving, for the server shutdown
+    pub fn close_archive(&mut self) {
+        self.archive.shutdown();
+    }
+
+    /// Returns the names of all logged in users, sorted
+    pub fn list_users(&self) -> Vec<String> {
+        let mut users: Vec<String> = self.shared_conn.keys().cloned().collect();
+        users.sort();
+        users
+    }
+
+    /// Returns all groups and their members, sorted by group name
+    pub fn list_groups(&self) -> Vec<(String, Vec<String>)> {
+        let mut groups: Vec<(String, Vec<String>)> = self.groups.iter()
+            .map(|(name, users)| (name.clone(), users.clone()))
+            .collect();
+        groups.sort();
+        groups
     }
 
     pub async fn join_group(&mut self, group_name: &str, username: &str) -> Result<(), io::Error> {
         if let Some(group) = self.groups.get_mut(group_name) {
-            // The group exists, if the user was already in the group, ignore request
-            if group.iter().find(|&x| *x == group_name.to_string()).is_some() {
+            // The group exists, if the user was already in the group (from another of its
+            // sessions), ignore request
+            if group.iter().any(|x| x == username) {
                 trace!("User {} wanted to join {} when already joined", username, group_name);
                 return Ok(())
             } else {
@@ -58,26 +345,38 @@ impl SharedConn {
                 group.push(username.to_string());
                 let notification = Command::ListUsr(
                     group_name.to_string(), ListUsrOperation::Add, format!("\n{}", username));
-                self.send2group(username, group_name, None, &notification).await?;
+                self.send2group(username, group_name, None, &notification)?;
             }
         } else {
             // The group does not exist, create the group and add the user to the group
             self.groups.insert(group_name.to_string(), vec![username.to_string()]);
         }
+        // Members on linked servers and other instances are notified by their own server
+        self.federation.joined(group_name, username);
+        self.cluster.joined(group_name, username);
         Ok(())
     }
 
     pub async fn leave_group(&mut self, username: &str, group_name: &str) -> Result<(), io::Error> {
+        self.leave(username, group_name)
+    }
+
+    fn leave(&mut self, username: &str, group_name: &str) -> Result<(), io::Error> {
         if let Some(group) = self.groups.get_mut(group_name) {
             if let Some(index) = group.iter().position(|name| name == username) {
                 // Remove the user from the goup
                 group.remove(index);
+                if group.is_empty() {
+                    self.archive.close(group_name);
+                }
 
                 // Notify other members about it, except to the user that leaves the group
                 let notification = Command::ListUsr(
                     group_name.to_string(), ListUsrOperation::Remove, format!("\n{}", username));
 
-                self.send2group(group_name, group_name, Some(vec![&username]), &notification).await?;
+                self.send2group(group_name, group_name, Some(vec![&username]), &notification)?;
+                self.federation.left(group_name, username);
+                self.cluster.left(group_name, username);
 
             } else {
                 return Err(io::Error::new(io::ErrorKind::NotFound, 
@@ -91,12 +390,34 @@ impl SharedConn {
         Ok(())
     }
     
-    pub async fn send(&mut self, 
+    /// Routes a MSG command of `sender` to its target user or group. The sender written by the
+    /// client is replaced, users can only send messages as themselves.
+    pub async fn send(&mut self, sender: &str,
                       command: Command) -> Result<(), io::Error>{
+        let command = match command {
+            Command::Msg(_, target, text) => Command::Msg(sender.to_string(), target, text),
+            command => command,
+        };
+        let to_group = match &command {
+            Command::Msg(_, target, _) => target.starts_with('#'),
+            _ => false,
+        };
+
+        let result = self.route(command).await;
+        match &result {
+            Ok(()) if to_group => Metrics::inc(&METRICS.messages_group),
+            Ok(()) => Metrics::inc(&METRICS.messages_direct),
+            Err(err) => METRICS.send_error(err.kind()),
+        }
+        result
+    }
+
+    async fn route(&mut self, 
+                   command: Command) -> Result<(), io::Error>{
 
         // Get the target's name from the MSG command
-        let (sender, target) = match &command {
-            Command::Msg(s,t,_) => (s, t),
+        let (sender, target, text) = match &command {
+            Command::Msg(s,t,x) => (s, t, x),
             _ => return Err(io::Error::new(
                     io::ErrorKind::InvalidInput, 
                     "Wrong command type. Only MSG commands can be sended")),
@@ -105,19 +426,32 @@ impl SharedConn {
         // Check if the target is a group
         if target.starts_with("#") {
             // Send the message to all participants of the group
-            return self.send2group(sender, target, None, &command).await;
+            return self.send2group(sender, target, None, &command);
         }
-        
-        // Get the target user's tx
-        let target_tx = match self.shared_conn.get_mut(&target.to_string()) {
-            Some(t) => t,
-            None => return Err(io::Error::new(
-                        io::ErrorKind::NotFound, 
-                        format!("Target {} not connected or does not exist", target))),
+
+        // Users of other servers are addressed as user@server
+        let target = match federation::split_address(target) {
+            Some((user, server)) if server == self.federation.name() => user,
+            Some(_) => return self.federation.send_direct(sender, target, text),
+            None => target,
         };
 
-        // Send the message
-        if let Err(_) = target_tx.send(command) {
+        // The user may also be logged in to other instances of the cluster
+        let remote = self.cluster.is_online(target);
+        if remote {
+            self.cluster.send_direct(sender, target, text);
+        }
+
+        // Send the message to every session of the user
+        if !self.shared_conn.contains_key(target) {
+            if remote {
+                return Ok(());
+            }
+            return Err(io::Error::new(
+                        io::ErrorKind::NotFound, 
+                        format!("Target {} not connected or does not exist", target)));
+        }
+        if !self.deliver(target, command.clone()) && !remote {
             return Err(io::Error::new(io::ErrorKind::BrokenPipe, 
                                       "Cannot transmit data to target"));
         }
@@ -126,7 +460,7 @@ impl SharedConn {
     
     /// ignore parameter is a list of usernames to ignore when sending the command.
     /// (feature needed for example, when notifying all memebers of a group that a user has gone)
-    async fn send2group(&mut self, sender: &str, 
+    fn send2group(&mut self, sender: &str, 
         target: &str, ignore: Option<Vec<&str>>, command: &Command) -> Result<(), io::Error>{
         
         // Get the target group, if group does not exist return an error
@@ -136,13 +470,28 @@ impl SharedConn {
                     format!("Target group {} does not exist", target))),
         };
         
+        // Members that joined from other instances of the cluster may have sessions here too
+        let remote_users: Vec<String> = self.cluster.remote_members(target).into_iter()
+            .filter(|name| !group_users.contains(name))
+            .collect();
+
         // If the sender is the target group, verify that 
         // the sender is a member from the target group.
-        if target != sender && group_users.iter().find(|&x| x == sender).is_none() {
+        if target != sender && !group_users.iter().chain(&remote_users).any(|x| x == sender) {
             return Err(io::Error::new(io::ErrorKind::PermissionDenied, 
                     format!("send2group error, sender {} is not a memeber of {}", sender, target)));
         }
 
+        for name in &remote_users {
+            let to_ignore = match &ignore {
+                Some(vec) => vec.iter().any(|x| x == name),
+                None => false,
+            };
+            if name != sender && !to_ignore {
+                self.deliver(name, command.clone());
+            }
+        }
+
         for name in group_users {
             // Check if the username is in the list to ignore
             let to_ignore = match &ignore {
@@ -153,21 +502,30 @@ impl SharedConn {
             };
             // Sender is always ignored
             if name != sender && !to_ignore {
-                // Get the Tx of the user
-                let user_tx = match self.shared_conn.get_mut(name) {
-                    Some(u) => u,
-                    None => return Err(io::Error::new(io::ErrorKind::NotFound,
+                // Members whose sessions are all on other instances get it from there
+                if !self.shared_conn.contains_key(name) && self.cluster.is_online(name) {
+                    continue;
+                }
+                if !self.shared_conn.contains_key(name) {
+                    return Err(io::Error::new(io::ErrorKind::NotFound,
                             format!("sender {} cannot find user {} in group {}", 
-                                sender, name, target))),
-                };
-                // Send a copy of the command to the user's Tx
-                if let Err(err) = user_tx.send(command.clone()) {
-                    return Err(io::Error::new(io::ErrorKind::PermissionDenied, 
-                            format!("Cannot send command to {} @ {}, unable to send over Tx: {}", 
-                                sender, target, err)));
+                                sender, name, target)));
+                }
+                // Send a copy of the command to every session of the user
+                if !self.deliver(name, command.clone()) {
+                    return Err(io::Error::new(io::ErrorKind::BrokenPipe, 
+                            format!("Cannot send command to {} @ {}, unable to send over Tx", 
+                                sender, target)));
                 }
             } 
         } 
+
+        // Archive the messages of the group, notifications are not part of the transcript
+        if let Command::Msg(_, _, text) = command {
+            self.archive.record(target, sender, text);
+            self.federation.group_message(target, sender, text);
+            self.cluster.group_message(target, group_users, sender, text);
+        }
         Ok(())
     }
     
@@ -176,8 +534,19 @@ impl SharedConn {
     /// TXT_BYTES section of an `ostrich-core` packet. If the usernames do not fit inside a single
     /// TXT_BYTES section, new string of usernames is appended to the returned vector.
     pub fn list_group(&self, group_name: &str) -> Result<Vec<String>, io::Error> {
-        let group = match self.groups.get(group_name) {
-            Some(g) => g,
+        // Members on other instances and linked servers are listed after the local ones
+        let cluster_members = self.cluster.remote_members(group_name);
+        let local = self.groups.get(group_name);
+        let remote: Vec<&String> = cluster_members.iter()
+            .filter(|name| match local {
+                Some(users) => !users.contains(name),
+                None => true,
+            })
+            .chain(self.federation.remote_members(group_name))
+            .collect();
+        let group: Vec<&String> = match local {
+            Some(g) => g.iter().chain(remote).collect(),
+            None if !remote.is_empty() => remote,
             None => return Err(io::Error::new(io::ErrorKind::InvalidInput, 
                     format!("Group {} does not exist", group_name))),
         };
@@ -205,28 +574,68 @@ impl SharedConn {
     }
 }
 
+/// Splits a text in chunks that fit inside the TXT_BYTES section of an `ostrich-core` packet.
+/// Lines are kept in separate chunks and long lines are cut at character boundaries.
+pub fn split_text(text: &str) -> Vec<String> {
+    let max = ostrich_core::TXT_BYTES.len();
+    let mut chunks = Vec::new();
+    for line in text.lines() {
+        let mut chunk = String::new();
+        for c in line.chars() {
+            if chunk.len() + c.len_utf8() > max {
+                chunks.push(chunk);
+                chunk = String::new();
+            }
+            chunk.push(c);
+        }
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+/// Byte stream a `Peer` exchanges command packets through: a TCP socket or a WebSocket
+/// connection. Every read yields at most one packet and every write is a whole packet.
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
 pub struct Peer {
-    socket: TcpStream,
+    socket: Box<dyn Connection>,
     rx: Rx,
-    pub groups: Vec<String>, // List of all group the user is member of
+    heartbeat: Option<Interval>,
+    last_seen: Instant,      // Last time something was received from the socket
 }
 
 impl Peer {
 
-    pub fn new(socket: TcpStream, rx: Rx) -> Peer {
-        Peer{ socket, rx , groups: Vec::new()}
+    pub fn new(socket: Box<dyn Connection>, rx: Rx) -> Peer {
+        Peer{ socket, rx, heartbeat: None, last_seen: Instant::now() }
+    }
+
+    /// Makes the peer's stream yield a `Message::Heartbeat` every `period`.
+    pub fn set_heartbeat(&mut self, period: Duration) {
+        self.heartbeat = Some(time::interval_at(Instant::now() + period, period));
+    }
+
+    /// Time elapsed since the last data was received from the peer
+    pub fn idle_time(&self) -> Duration {
+        self.last_seen.elapsed()
     }
 
     pub async fn send_command(&mut self, command: &Command) -> Result<usize, io::Error> {
-        self.socket.write(&RawMessage::to_raw(command)?).await
+        let raw = RawMessage::to_raw(command)?;
+        self.socket.write_all(&raw).await?;
+        self.socket.flush().await?;
+        Ok(raw.len())
     }
 
     pub async fn read_command(&mut self) -> Result<Option<Command>, io::Error> {
         let mut buffer = [0u8;PCK_SIZE];
         let n = self.socket.read(&mut buffer).await?;
+        self.last_seen = Instant::now();
 
         if n == 0 {
-            println!("returning ok");
+            trace!("Connection closed by the peer");
             return Ok(None);
         }
         // else 
@@ -236,9 +645,23 @@ impl Peer {
     
 }
 
+impl Drop for Peer {
+    fn drop(&mut self) {
+        // Account for the commands that will never be delivered
+        self.rx.close();
+        while let Ok(outgoing) = self.rx.try_recv() {
+            if let Outgoing::Command(_, _) = outgoing {
+                METRICS.discarded();
+            }
+        }
+    }
+}
+
 pub enum Message {
     ToSend(Command),
     Received(Command),
+    Heartbeat, // Time to check if the peer is still alive
+    Close(String), // The server asked to close the session
 }
 
 impl Stream for Peer {
@@ -250,7 +673,21 @@ impl Stream for Peer {
         
         // Check if we have received something
         if let Poll::Ready(Some(v)) = Pin::new(&mut self.rx).poll_next(cx) {
-            return Poll::Ready(Some(Ok(Message::Received(v))));
+            let message = match v {
+                Outgoing::Command(command, queued) => {
+                    METRICS.dequeued(queued.elapsed());
+                    Message::Received(command)
+                },
+                Outgoing::Close(reason) => Message::Close(reason),
+            };
+            return Poll::Ready(Some(Ok(message)));
+        }
+
+        // Check if it's time to send a heartbeat
+        if let Some(heartbeat) = self.heartbeat.as_mut() {
+            if heartbeat.poll_tick(cx).is_ready() {
+                return Poll::Ready(Some(Ok(Message::Heartbeat)));
+            }
         }
 
         // Check if we have received something
@@ -260,6 +697,7 @@ impl Stream for Peer {
             Poll::Ready(Err(err)) => return Poll::Ready(Some(Err(err))),
             Poll::Pending => return Poll::Pending,
         };
+        self.last_seen = Instant::now();
         
         if n > 0 {
             let command = RawMessage::from_raw(&data)?;
@@ -326,7 +764,8 @@ impl DataBase {
     }
     
     // Returns the username and password from the user input 
-    pub fn check_log_in_credentials(&self, command: Command) -> Result<String, io::Error> {
+    pub fn check_log_in_credentials(&self, command: Command, 
+                                    bans: &ban::BanList) -> Result<String, io::Error> {
         // Check if the command is USR login command, and get username and password
         let (username, password) = match &command {
             Command::Usr(u, p) => (u, p),
@@ -334,6 +773,24 @@ impl DataBase {
                                            "Incorrect log in command")),
         };
 
+        // Names starting with '!' are reserved for the server
+        if username.starts_with('!') {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                      "Usernames starting with '!' are reserved"));
+        }
+
+        // '@' separates the username from the server in the addresses of federated users
+        if username.contains('@') {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                      "Usernames cannot contain '@'"));
+        }
+
+        // Check that the user is not banned
+        if let Some(ban) = bans.check_user(username) {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                      format!("Banned: {}", ban.reason)));
+        }
+
         // Create a user with the given username and password
         let usr = User {
             name: username.clone().to_string(),
@@ -358,3 +815,79 @@ impl DataBase {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server() -> SharedConn {
+        SharedConn::new(Archive::disabled(), Federation::disabled(), Cluster::disabled())
+    }
+
+    fn login(conn: &mut SharedConn, name: &str, session: SessionId) -> Rx {
+        let (tx, rx) = mpsc::unbounded_channel();
+        conn.add(name.to_string(), session, tx, 1).unwrap();
+        rx
+    }
+
+    /// Commands and close requests queued to a session
+    fn received(rx: &mut Rx) -> (Vec<Command>, Vec<String>) {
+        let (mut commands, mut closes) = (Vec::new(), Vec::new());
+        while let Ok(outgoing) = rx.try_recv() {
+            match outgoing {
+                Outgoing::Command(command, _) => commands.push(command),
+                Outgoing::Close(reason) => closes.push(reason),
+            }
+        }
+        (commands, closes)
+    }
+
+    #[tokio::test]
+    async fn ghosted_sessions_are_closed_and_leave_their_groups() {
+        let mut conn = server();
+        let mut ghost = login(&mut conn, "alice", 1);
+        let mut bob = login(&mut conn, "bob", 2);
+        conn.join_group("#g", "alice").await.unwrap();
+        conn.join_group("#g", "bob").await.unwrap();
+        received(&mut bob);
+
+        let (tx, _rx) = mpsc::unbounded_channel();
+        assert!(conn.add("alice".to_string(), 3, tx, 1).is_err());
+        assert_eq!(conn.take_over("alice", "Taken over").await.unwrap(), 1);
+        assert_eq!(received(&mut ghost).1, vec!["Taken over".to_string()]);
+        let left = Command::ListUsr("#g".to_string(), ListUsrOperation::Remove, "\nalice".to_string());
+        assert_eq!(received(&mut bob).0, vec![left]);
+        assert_eq!(conn.list_group("#g").unwrap(), vec!["bob\n".to_string()]);
+
+        // The old session ending later on does not affect the new one
+        let _alice = login(&mut conn, "alice", 3);
+        assert!(conn.remove("alice", 1).await.is_err());
+        assert_eq!(conn.session_count("alice"), 1);
+
+        let err = conn.take_over("carol", "Taken over").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn messages_carry_the_real_sender() {
+        let mut conn = server();
+        let _alice = login(&mut conn, "alice", 1);
+        let mut bob = login(&mut conn, "bob", 2);
+        conn.join_group("#g", "bob").await.unwrap();
+        received(&mut bob);
+
+        let forged = |sender: &str, target: &str| {
+            Command::Msg(sender.to_string(), target.to_string(), "hi".to_string())
+        };
+        conn.send("alice", forged(SERVER_NAME, "bob")).await.unwrap();
+        conn.send("alice", forged("carol", "bob")).await.unwrap();
+        assert_eq!(received(&mut bob).0, vec![forged("alice", "bob"), forged("alice", "bob")]);
+
+        // Claiming to be a member does not allow writing to the group
+        let err = conn.send("alice", forged("bob", "#g")).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        let err = conn.send("alice", forged("#g", "#g")).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(received(&mut bob).0.is_empty());
+    }
+}
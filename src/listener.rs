@@ -0,0 +1,142 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, UnixListener};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::{NoClientAuth, ServerConfig};
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+
+use std::fmt;
+use std::fs::{self, DirBuilder, File, Permissions};
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+
+use crate::Connection;
+
+/// Address of a connected client
+#[derive(Debug, Clone)]
+pub enum PeerAddr {
+    Ip(SocketAddr),
+    Unix(String), // Path of the socket the client connected to
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Ip(addr) => write!(f, "{}", addr),
+            PeerAddr::Unix(path) => write!(f, "unix:{}", path),
+        }
+    }
+}
+
+/// Socket accepting client connections
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, String), // Listener, path of the socket file
+}
+
+impl Listener {
+
+    pub async fn accept(&mut self) -> Result<(Box<dyn Connection>, PeerAddr), io::Error> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Box::new(stream), PeerAddr::Ip(addr)))
+            },
+            Listener::Unix(listener, path) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), PeerAddr::Unix(path.clone())))
+            },
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => write!(f, "tcp"),
+            },
+            Listener::Unix(_, path) => write!(f, "unix:{}", path),
+        }
+    }
+}
+
+/// Binds a TCP listener. IPv6 listeners also accept IPv4 clients (as IPv4-mapped addresses)
+/// unless `v6_only` is set, regardless of the system default.
+pub fn bind_tcp(addr: SocketAddr, v6_only: bool) -> Result<TcpListener, io::Error> {
+    let domain = if addr.is_ipv6() { Domain::ipv6() } else { Domain::ipv4() };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    let listener = socket.into_tcp_listener();
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+/// Loads a PEM certificate chain and its private key (PKCS#8 or RSA) for a TLS listener
+pub fn load_tls(cert_path: &str, key_path: &str) -> Result<TlsAcceptor, io::Error> {
+    let invalid = |what: &str, path: &str| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("no valid {} found in {}", what, path))
+    };
+
+    let chain = certs(&mut BufReader::new(File::open(cert_path)?))
+        .map_err(|_| invalid("certificate", cert_path))?;
+    if chain.is_empty() {
+        return Err(invalid("certificate", cert_path));
+    }
+
+    let mut keys = pkcs8_private_keys(&mut BufReader::new(File::open(key_path)?))
+        .map_err(|_| invalid("private key", key_path))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut BufReader::new(File::open(key_path)?))
+            .map_err(|_| invalid("private key", key_path))?;
+    }
+    let key = keys.into_iter().next().ok_or_else(|| invalid("private key", key_path))?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(chain, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Binds a Unix domain socket at `path`, replacing the socket file left by a previous run.
+/// Access to the socket is restricted with the given file permission `mode`. The socket is
+/// created in a private directory and moved to `path` once restricted, so it cannot be
+/// connected to with the default permissions in between.
+pub fn bind_unix(path: &str, mode: u32) -> Result<UnixListener, io::Error> {
+    remove_unix(path)?;
+
+    let dir = format!("{}.{}.tmp", path, process::id());
+    if Path::new(&dir).exists() {
+        fs::remove_dir_all(&dir)?; // Left by a crashed run that had the same process id
+    }
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    let tmp_path = Path::new(&dir).join("socket");
+    let result = UnixListener::bind(&tmp_path).and_then(|listener| {
+        fs::set_permissions(&tmp_path, Permissions::from_mode(mode))?;
+        fs::rename(&tmp_path, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+/// Removes the socket file at `path`, if any. Other kinds of files are left alone.
+pub fn remove_unix(path: &str) -> Result<(), io::Error> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path),
+        Ok(_) => Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                    format!("{} exists and is not a socket", path))),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
@@ -0,0 +1,96 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Log file opened in append mode that rotates itself by size and/or age.
+/// Rotated files are named `<path>.1` (newest) to `<path>.<retention>` (oldest),
+/// older files are deleted.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,               // Current size of the file in bytes
+    opened: Instant,         // When the current file started to be used
+    max_size: u64,           // Rotate when the file would exceed this size (0 disables)
+    max_age: Duration,       // Rotate when the file is this old (zero disables)
+    retention: usize,        // Number of rotated files to keep
+    line_start: bool,        // Last write ended a line, records are never split
+}
+
+impl RotatingFile {
+
+    pub fn open<P: AsRef<Path>>(path: P,
+                                max_size: u64,
+                                max_age: Duration,
+                                retention: usize) -> Result<RotatingFile, io::Error> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile { 
+            path, file, size, opened: Instant::now(), max_size, max_age, retention,
+            line_start: true,
+        })
+    }
+
+    fn needs_rotation(&self, incoming: usize) -> bool {
+        let too_big = self.max_size > 0 && self.size > 0 
+            && self.size + incoming as u64 > self.max_size;
+        let too_old = self.max_age > Duration::from_secs(0) && self.opened.elapsed() >= self.max_age;
+        too_big || too_old
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> Result<(), io::Error> {
+        self.file.flush()?;
+
+        if self.retention == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            // Drop the oldest file and shift the rest one position
+            let oldest = self.rotated_path(self.retention);
+            if oldest.exists() {
+                fs::remove_file(&oldest)?;
+            }
+            for index in (1..self.retention).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.line_start && self.needs_rotation(buf.len()) {
+            if let Err(err) = self.rotate() {
+                // Keep logging to the current file rather than losing the record
+                eprintln!("Cannot rotate log file {}: {}", self.path.display(), err);
+                self.opened = Instant::now();
+            }
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        if n > 0 {
+            self.line_start = buf[n - 1] == b'\n';
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
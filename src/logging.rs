@@ -0,0 +1,175 @@
+use chrono::{SecondsFormat, Utc};
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde::{Deserialize, Serialize};
+
+use crate::config::LoggingConfig;
+
+use std::future::Future;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+/// Output format of the log records
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json, // One JSON object per line
+}
+
+/// Identifies the connection a log record comes from
+pub struct Session {
+    pub id: u64,
+    pub peer: String,
+    user: Mutex<Option<String>>, // Set once the user logs in
+}
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+tokio::task_local! {
+    static SESSION: Arc<Session>;
+}
+
+impl Session {
+
+    /// Creates a session with a new unique id for a connection from `peer`
+    pub fn new(peer: String) -> Arc<Session> {
+        let id = NEXT_SESSION_ID.fetch_add(1, Ordering::SeqCst);
+        Arc::new(Session { id, peer, user: Mutex::new(None) })
+    }
+
+    /// IP address of the peer, None for Unix domain sockets
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.peer.parse::<SocketAddr>().ok().map(|addr| addr.ip())
+    }
+
+    pub fn user(&self) -> Option<String> {
+        self.user.lock().ok().and_then(|u| u.clone())
+    }
+
+    /// Runs the future with the session attached to every log record it emits
+    pub async fn scope<F: Future>(self: Arc<Session>, f: F) -> F::Output {
+        SESSION.scope(self, f).await
+    }
+
+    /// Returns the session of the running task, if any
+    pub fn current() -> Option<Arc<Session>> {
+        SESSION.try_with(|s| Arc::clone(s)).ok()
+    }
+
+    /// Sets the username of the session of the running task
+    pub fn set_current_user(name: &str) {
+        if let Some(session) = Session::current() {
+            if let Ok(mut user) = session.user.lock() {
+                *user = Some(name.to_string());
+            }
+        }
+    }
+}
+
+/// Levels of the terminal and file outputs and format of both, kept outside the logger so a
+/// reload can change them while it runs
+static TERMINAL_LEVEL: AtomicUsize = AtomicUsize::new(0);
+static FILE_LEVEL: AtomicUsize = AtomicUsize::new(0);
+static JSON_FORMAT: AtomicBool = AtomicBool::new(false);
+
+const LEVELS: [LevelFilter; 6] = [LevelFilter::Off, LevelFilter::Error, LevelFilter::Warn,
+                                  LevelFilter::Info, LevelFilter::Debug, LevelFilter::Trace];
+
+fn level(stored: &AtomicUsize) -> LevelFilter {
+    LEVELS[stored.load(Ordering::Relaxed).min(LEVELS.len() - 1)]
+}
+
+/// Logger writing every record to the terminal (errors to stderr, the rest to stdout) and to
+/// a file, each with its own level. Records emitted inside a `Session::scope` carry the
+/// session id, the peer address and the username of the connection.
+pub struct Logger {
+    file: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Logger {
+
+    /// Installs the logger as the global logger
+    pub fn init<W: Write + Send + 'static>(file: W, config: &LoggingConfig) -> Result<(), SetLoggerError> {
+        log::set_boxed_logger(Box::new(Logger { file: Mutex::new(Box::new(file)) }))?;
+        Logger::configure(config);
+        Ok(())
+    }
+
+    /// Applies the levels, the format and the terminal setting of `config` to the logger
+    pub fn configure(config: &LoggingConfig) {
+        let terminal = if config.terminal { config.terminal_level } else { LevelFilter::Off };
+        TERMINAL_LEVEL.store(terminal as usize, Ordering::Relaxed);
+        FILE_LEVEL.store(config.file_level as usize, Ordering::Relaxed);
+        JSON_FORMAT.store(config.format == LogFormat::Json, Ordering::Relaxed);
+        log::set_max_level(terminal.max(config.file_level));
+    }
+}
+
+fn format_text(record: &Record, time: &str, session: &Option<Arc<Session>>) -> String {
+    let context = match session {
+        Some(s) => match s.user() {
+            Some(user) => format!("[session={} peer={} user={}] ", s.id, s.peer, user),
+            None => format!("[session={} peer={}] ", s.id, s.peer),
+        },
+        None => String::new(),
+    };
+    format!("{} {:<5} {}{}: {}\n", time, record.level(), context, record.target(), record.args())
+}
+
+fn format_json(record: &Record, time: &str, session: &Option<Arc<Session>>) -> String {
+    let mut object = json::object!{
+        "time" => time,
+        "level" => record.level().to_string(),
+        "target" => record.target(),
+        "message" => record.args().to_string(),
+    };
+    if let Some(s) = session {
+        let _ = object.insert("session", s.id);
+        let _ = object.insert("peer", s.peer.clone());
+        if let Some(user) = s.user() {
+            let _ = object.insert("user", user);
+        }
+    }
+    format!("{}\n", object.dump())
+}
+
+impl Log for Logger {
+
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= level(&TERMINAL_LEVEL) || metadata.level() <= level(&FILE_LEVEL)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let time = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        let session = Session::current();
+        let line = if JSON_FORMAT.load(Ordering::Relaxed) {
+            format_json(record, &time, &session)
+        } else {
+            format_text(record, &time, &session)
+        };
+
+        if record.level() <= level(&TERMINAL_LEVEL) {
+            let _ = match record.level() {
+                Level::Error => io::stderr().write_all(line.as_bytes()),
+                _ => io::stdout().write_all(line.as_bytes()),
+            };
+        }
+        if record.level() <= level(&FILE_LEVEL) {
+            if let Ok(mut file) = self.file.lock() {
+                let _ = file.write_all(line.as_bytes());
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut file) = self.file.lock() {
+            let _ = file.flush();
+        }
+        let _ = io::stdout().flush();
+    }
+}
@@ -1,50 +1,86 @@
 use ostrich_core::*;
 
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{Mutex, mpsc};
+use tokio_rustls::TlsAcceptor;
+use tokio::sync::{Mutex, mpsc, watch};
+use tokio::time::{self, Instant};
+use tokio::signal::{self, unix::{signal, SignalKind}};
 
 use std::io;
 use std::sync::Arc;
-use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::process;
+use std::time::Duration;
 
 use tokio::stream::{StreamExt};
 use ostrich_server::{
-    SharedConn, Message, Peer, 
-    // NOTE: Renamed to avoid conflic with simplelog::Config
-    DataBase, config::Config as ServerConfig 
+    SharedConn, SessionId, Message, Peer, Connection, websocket,
+    listener::{self, Listener, PeerAddr}, systemd, proxy::{self, TrustedProxies},
+    DataBase, config::{Config as ServerConfig, ListenerConfig, Overrides}, reload::reload,
+    admin::{self, AdminContext, AdminCommand}, SERVER_NAME, split_text,
+    http, metrics::{Metrics, METRICS}, health::{self, Health},
+    logfile::RotatingFile, ban::BanList, audit::AuditLog, archive::Archive, logging::{Logger, Session},
+    federation::{self, Federation}, backplane::{self, Backplane, Cluster, RedisBackplane},
 };
 
-#[macro_use] extern crate log;
-extern crate simplelog;
-
-use simplelog::*;
+mod cli;
 
-use std::fs::File;
+#[macro_use] extern crate log;
 
 #[tokio::main]
 async fn main() -> Result<(), io::Error> {
 
-    let server_config = ServerConfig::new("config.toml")
+    let args = cli::Args::parse();
+
+    let mut server_config = ServerConfig::new(&args.config_path)
         .unwrap_or_else(|err| {
             eprintln!("Fatal error reading ostrich-server config file: {}",
                 err);
             process::exit(-1);
         });
+    args.overrides.apply(&mut server_config);
+
+    if args.check_config {
+        check_config(&args.config_path, &server_config);
+    }
+    if let Err(err) = server_config.check_keepalive() {
+        eprintln!("Fatal error in ostrich-server config file: {}", err);
+        process::exit(-1);
+    }
         
     // Initialize server logger
-    if let Err(err) = CombinedLogger::init(vec![
-            TermLogger::new(LevelFilter::Trace, Config::default(), 
-                TerminalMode::Mixed).unwrap(),
-            WriteLogger::new(LevelFilter::Info, Config::default(), 
-                File::create(server_config.logger_file.clone()).unwrap())]) {
+    let logging = &server_config.logging;
+    let log_file = RotatingFile::open(&server_config.logger_file,
+                                      logging.max_size,
+                                      Duration::from_secs(logging.rotate_interval),
+                                      logging.retention)
+        .unwrap_or_else(|err| {
+            eprintln!("Fatal: Could not open log file {}: {}", server_config.logger_file, err);
+            process::exit(-1);
+        });
 
+    if let Err(err) = Logger::init(log_file, logging) {
         eprintln!("Fatal: Could not initialize the logger: {}", err);
         process::exit(-1);
     }
 
     info!("Ostrich server initialized!");
     info!("logger's output file path: {}", server_config.logger_file);
+    for var in &server_config.unknown_env {
+        warn!("Ignoring {}, it does not match any setting", var);
+    }
+
+    // Open the security audit log
+    let audit = if server_config.audit_file.is_empty() {
+        AuditLog::disabled()
+    } else {
+        AuditLog::open(&server_config.audit_file, server_config.audit_hash_chain)
+            .unwrap_or_else(|err| {
+                error!("Cannot open the audit log {}: {}", server_config.audit_file, err);
+                process::exit(1);
+            })
+    };
+    let audit = Arc::new(audit);
 
     // Load the DataBase 
     let db = match DataBase::new(&server_config.database_file) {
@@ -59,91 +95,761 @@ async fn main() -> Result<(), io::Error> {
     };
     let db = Arc::new(Mutex::new(db));
 
-    let shared_conn = Arc::new(Mutex::new(SharedConn::new()));
+    // Load the server bans
+    let bans = match BanList::load(&server_config.ban_file) {
+        Ok(bans) => {
+            info!("Bans loaded from {}", server_config.ban_file);
+            bans
+        },
+        Err(err) => {
+            error!("Cannot load bans from {}: {}", server_config.ban_file, err);
+            process::exit(1);
+        },
+    };
+    let bans = Arc::new(Mutex::new(bans));
 
-    let addr = format!("{}:{}", 
-        server_config.ip_address,
-        server_config.port);
+    // Share the users and groups with the other instances of the cluster
+    let backplane = if server_config.backplane.redis_address.is_empty() {
+        None
+    } else {
+        if server_config.backplane.instance.is_empty() {
+            error!("The backplane needs an instance name, set instance in [backplane]");
+            process::exit(1);
+        }
+        info!("Joining the cluster as {} through Redis at {}",
+              server_config.backplane.instance, server_config.backplane.redis_address);
+        Some(Arc::new(RedisBackplane::connect(&server_config.backplane)))
+    };
+    let cluster = match &backplane {
+        Some(backplane) => Cluster::new(Arc::clone(backplane) as Arc<dyn Backplane>),
+        None => Cluster::disabled(),
+    };
 
-    // Bind a TCP listener to the socket address
-    let mut listener = TcpListener::bind(&addr).await?;
-    info!("server running on {}", addr);
+    let shared_conn = Arc::new(Mutex::new(SharedConn::new(
+        Archive::new(server_config.archive.clone()),
+        Federation::new(&server_config.federation.server_name),
+        cluster)));
+    if let Some(backplane) = &backplane {
+        let heartbeat = Duration::from_secs(server_config.backplane.heartbeat_interval.max(1));
+        backplane::start(backplane.as_ref(), Arc::clone(&shared_conn), heartbeat);
+    }
 
-    loop {
-        // Asynchronously wait for an inbound TcpStream.
-        let (stream, addr) = listener.accept().await?;
+    let health = Arc::new(Health::new());
+    health.database_loaded.store(true, Ordering::SeqCst);
+    tokio::spawn(health::watchdog(Arc::clone(&health), Arc::clone(&shared_conn)));
+
+    // Number of connections that are still in the log in phase
+    let pending = Arc::new(AtomicUsize::new(0));
 
-        // Clone a handle to the `ConnectedUsers` state for the new connection.
-        let world = Arc::clone(&shared_conn);
-        let data = Arc::clone(&db);
+    // Number of open connections, used to wait for sessions to end on shutdown
+    let active = Arc::new(AtomicUsize::new(0));
 
-        // Spawn our handler to be run asynchronously.
+    // Use the sockets passed by systemd socket activation, or bind the configured ones
+    let activated = systemd::listen_fds().unwrap_or_else(|err| {
+        error!("Cannot use the sockets passed by systemd: {}", err);
+        process::exit(1);
+    });
+    let mut unix_socket = String::new(); // Socket file to remove on shutdown
+    let mut listeners = Vec::new();
+
+    if !activated.is_empty() {
+        info!("Using {} sockets passed by systemd", activated.len());
+        let configs = server_config.all_listeners();
+        for listener in activated {
+            match activated_endpoint(&listener, &configs) {
+                Ok(endpoint) => listeners.push((listener, endpoint)),
+                Err(err) => error!("Cannot use socket {} passed by systemd: {}", listener, err),
+            }
+        }
+    } else {
+        // A listener that cannot be bound is reported and skipped, so one bad address does
+        // not take down the others.
+        for config in server_config.all_listeners() {
+            match bind(&config) {
+                Ok(listener) => listeners.push(listener),
+                Err(err) => error!("Cannot listen on {}: {}", config.address, err),
+            }
+        }
+
+        // Bind the Unix domain socket listener for local clients
+        if !server_config.unix_socket.is_empty() {
+            let path = server_config.unix_socket.clone();
+            match listener::bind_unix(&path, server_config.unix_socket_mode) {
+                Ok(listener) => {
+                    let raw = Endpoint::raw();
+                    listeners.push((Listener::Unix(listener, path.clone()), raw));
+                    unix_socket = path;
+                },
+                Err(err) => error!("Cannot listen on unix:{}: {}", path, err),
+            }
+        }
+    }
+
+    for (listener, endpoint) in &listeners {
+        info!("server running on {} ({:?}{})", listener, endpoint.framing,
+              if endpoint.tls.is_some() { ", TLS" } else { "" });
+    }
+    if listeners.is_empty() {
+        error!("No listener could be started, check ip_address, listeners and unix_socket");
+        process::exit(1);
+    }
+    health.listening.store(true, Ordering::SeqCst);
+
+    let admin_socket = server_config.admin_socket.clone();
+    let http_address = server_config.http_address.clone();
+    let admin_socket_mode = server_config.admin_socket_mode;
+    let federation_config = server_config.federation.clone();
+
+    // From now on, the configuration can be reloaded with SIGHUP
+    let server_config = Arc::new(Mutex::new(server_config));
+    tokio::spawn(reload_on_hangup(args.config_path.clone(), args.overrides.clone(),
+                                  Arc::clone(&server_config), Arc::clone(&db)));
+
+    // State used to run admin commands, from the admin socket or from admin users
+    let admin_ctx = Arc::new(AdminContext {
+        shared_conn: Arc::clone(&shared_conn),
+        db: Arc::clone(&db),
+        bans: Arc::clone(&bans),
+        config: Arc::clone(&server_config),
+        config_path: args.config_path.clone(),
+        overrides: args.overrides.clone(),
+        connections: Arc::clone(&active),
+        pending: Arc::clone(&pending),
+        health: Arc::clone(&health),
+        audit: Arc::clone(&audit),
+        started: Instant::now(),
+    });
+
+    // Start the admin control socket
+    if !admin_socket.is_empty() {
+        let ctx = Arc::clone(&admin_ctx);
+        let path = admin_socket.clone();
         tokio::spawn(async move {
-            if let Err(e) = process(world, data, stream, addr).await {
-                error!("User dropped with error, ERROR: {:?}", e);
+            if let Err(err) = admin::serve(&path, admin_socket_mode, ctx).await {
+                error!("Admin socket {} error: {}", path, err);
             }
         });
     }
+
+    // Start the HTTP monitoring endpoint
+    if !http_address.is_empty() {
+        let ctx = Arc::clone(&admin_ctx);
+        tokio::spawn(async move {
+            if let Err(err) = http::serve(&http_address, ctx).await {
+                error!("HTTP endpoint {} error: {}", http_address, err);
+            }
+        });
+    }
+
+    // Link with the other servers of the federation
+    if !federation_config.server_name.is_empty() {
+        let name = federation_config.server_name.clone();
+        match federation::start(federation_config, Arc::clone(&shared_conn), Arc::clone(&audit)).await {
+            Ok(Some(addr)) => info!("Federated as {}, accepting server links on {}", name, addr),
+            Ok(None) => info!("Federated as {}", name),
+            Err(err) => {
+                error!("Cannot start the federation: {}", err);
+                process::exit(1);
+            },
+        }
+    }
+
+    // Accept connections until the server is asked to stop
+    let (stop, stopped) = watch::channel(false);
+    let listeners: Vec<_> = listeners.into_iter()
+        .map(|(listener, endpoint)| {
+            tokio::spawn(listen(listener, endpoint, Arc::clone(&admin_ctx), stopped.clone()))
+        })
+        .collect();
+
+    health.ready.store(true, Ordering::SeqCst);
+    systemd::notify("READY=1");
+
+    if let Err(err) = shutdown_signal().await {
+        error!("Error listening for shutdown signals: {}", err);
+    }
+
+    // Stop accepting connections and ask every session to close
+    health.ready.store(false, Ordering::SeqCst);
+    systemd::notify("STOPPING=1");
+    let _ = stop.broadcast(true);
+    for listener in listeners {
+        let _ = listener.await;
+    }
+    health.listening.store(false, Ordering::SeqCst);
+    info!("Shutting down, notifying {} connections", active.load(Ordering::SeqCst));
+    shared_conn.lock().await.close_all("Server shutting down");
+
+    // Give sessions some time to send their queued data and exit
+    let shutdown_timeout = Duration::from_secs(server_config.lock().await.shutdown_timeout);
+    let deadline = Instant::now() + shutdown_timeout;
+    while active.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+        time::delay_for(Duration::from_millis(100)).await;
+    }
+    let remaining = active.load(Ordering::SeqCst);
+    if remaining > 0 {
+        warn!("Shutdown timeout reached, dropping {} connections", remaining);
+    }
+    shared_conn.lock().await.close_archive();
+
+    if !admin_socket.is_empty() {
+        let _ = listener::remove_unix(&admin_socket);
+    }
+    if !unix_socket.is_empty() {
+        let _ = listener::remove_unix(&unix_socket);
+    }
+
+    info!("Ostrich server stopped");
+    Ok(())
+}
+
+/// Completes when the server receives SIGINT or SIGTERM
+async fn shutdown_signal() -> Result<(), io::Error> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        res = signal::ctrl_c() => res,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+/// Completes when the server starts shutting down
+async fn stopped(mut stop: watch::Receiver<bool>) {
+    while let Some(false) = stop.recv().await {}
+}
+
+/// How the connections of a listener carry the command packets
+#[derive(Clone, Copy, Debug)]
+enum Framing {
+    Raw,       // `ostrich-core` packets straight over the stream
+    WebSocket, // WebSocket messages, see the `websocket` module
+}
+
+/// Handshakes a listener runs on its connections before the log in
+#[derive(Clone)]
+struct Endpoint {
+    framing: Framing,
+    tls: Option<TlsAcceptor>,
+    proxies: Option<Arc<TrustedProxies>>, // Set when the PROXY protocol is enabled
+}
+
+impl Endpoint {
+
+    /// Returns the endpoint of a configured listener, loading its TLS certificate if any
+    fn new(config: &ListenerConfig) -> Result<Endpoint, io::Error> {
+        if config.tls_cert.is_empty() != config.tls_key.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "tls_cert and tls_key must be set together"));
+        }
+        let tls = if config.is_tls() {
+            Some(listener::load_tls(&config.tls_cert, &config.tls_key)?)
+        } else {
+            None
+        };
+        let framing = if config.websocket { Framing::WebSocket } else { Framing::Raw };
+        let proxies = if config.proxy_protocol {
+            let proxies = TrustedProxies::parse(&config.trusted_proxies)?;
+            if proxies.is_empty() {
+                warn!("PROXY protocol enabled on {} without trusted proxies, it will not be used",
+                      config.address);
+            }
+            Some(Arc::new(proxies))
+        } else {
+            None
+        };
+        Ok(Endpoint { framing, tls, proxies })
+    }
+
+    /// Plain `ostrich-core` connections, without TLS nor PROXY protocol
+    fn raw() -> Endpoint {
+        Endpoint { framing: Framing::Raw, tls: None, proxies: None }
+    }
+
+    /// Returns true if the connection comes from a trusted proxy that sends a PROXY header
+    fn is_trusted_proxy(&self, addr: &PeerAddr) -> bool {
+        match (&self.proxies, addr) {
+            (Some(proxies), PeerAddr::Ip(addr)) => proxies.contains(addr.ip()),
+            _ => false,
+        }
+    }
+
+    /// Performs the TLS and WebSocket handshakes of the endpoint on a new connection
+    async fn handshake(&self, stream: Box<dyn Connection>) -> Result<Box<dyn Connection>, io::Error> {
+        let stream: Box<dyn Connection> = match &self.tls {
+            Some(acceptor) => Box::new(acceptor.accept(stream).await?),
+            None => stream,
+        };
+        match self.framing {
+            Framing::Raw => Ok(stream),
+            Framing::WebSocket => Ok(Box::new(websocket::accept(stream).await?)),
+        }
+    }
+}
+
+fn resolve(address: &str) -> Result<SocketAddr, io::Error> {
+    address.to_socket_addrs()?.next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "address not found"))
+}
+
+/// Binds a TCP listener as configured
+fn bind(config: &ListenerConfig) -> Result<(Listener, Endpoint), io::Error> {
+    let addr = resolve(&config.address)?;
+    let endpoint = Endpoint::new(config)?;
+    let listener = listener::bind_tcp(addr, config.v6_only)?;
+    Ok((Listener::Tcp(listener), endpoint))
+}
+
+/// Returns the endpoint of a socket passed by systemd: the one of the configured listener
+/// with the same address, so TLS and WebSocket settings still apply, or plain `ostrich-core`.
+fn activated_endpoint(listener: &Listener, configs: &[ListenerConfig]) -> Result<Endpoint, io::Error> {
+    if let Listener::Tcp(tcp) = listener {
+        let local = tcp.local_addr()?;
+        for config in configs {
+            if resolve(&config.address).ok() == Some(local) {
+                return Endpoint::new(config);
+            }
+        }
+    }
+    Ok(Endpoint::raw())
+}
+
+/// Accepts connections until the server shuts down, starting a session for each of them
+async fn listen(mut listener: Listener,
+                endpoint: Endpoint,
+                admin: Arc<AdminContext>,
+                stop: watch::Receiver<bool>) {
+    let stopped = stopped(stop);
+    tokio::pin!(stopped);
+
+    loop {
+        // Asynchronously wait for an inbound connection or a shutdown signal.
+        let (stream, addr) = tokio::select! {
+            res = listener.accept() => match res {
+                Ok(conn) => conn,
+                Err(err) => {
+                    // Usually out of file descriptors, wait a bit for some to be released
+                    error!("Cannot accept connection: {}", err);
+                    time::delay_for(Duration::from_millis(100)).await;
+                    continue;
+                },
+            },
+            _ = &mut stopped => break,
+        };
+        accept(stream, addr, &endpoint, &admin).await;
+    }
+}
+
+/// Starts the session of an accepted connection, unless its address is banned or too many
+/// connections are waiting to log in.
+async fn accept(mut stream: Box<dyn Connection>,
+                addr: PeerAddr,
+                endpoint: &Endpoint,
+                admin: &Arc<AdminContext>) {
+    Metrics::inc(&METRICS.connections);
+
+    // Connections from trusted proxies are checked once the client address is known
+    let behind_proxy = endpoint.is_trusted_proxy(&addr);
+
+    // Drop connections from banned addresses
+    if !behind_proxy && is_banned(admin, &addr).await {
+        return;
+    }
+
+    // Get the current settings for the new connection
+    let (max_pending, login_timeout, keepalive) = {
+        let cfg = admin.config.lock().await;
+        (cfg.max_pending_logins,
+         Duration::from_secs(cfg.login_timeout),
+         KeepAlive {
+             interval: Duration::from_secs(cfg.keepalive_interval),
+             idle_timeout: Duration::from_secs(cfg.idle_timeout),
+         })
+    };
+
+    // Refuse the connection if too many clients are waiting to log in
+    if admin.pending.load(Ordering::SeqCst) >= max_pending {
+        warn!("Too many pending log ins, dropping connection from {}", addr);
+        Metrics::inc(&METRICS.connections_refused);
+        return;
+    }
+    let login_slot = Slot::new(Arc::clone(&admin.pending));
+    let conn_slot = Slot::new(Arc::clone(&admin.connections));
+
+    // Clone a handle to the `ConnectedUsers` state for the new connection.
+    let world = Arc::clone(&admin.shared_conn);
+    let data = Arc::clone(&admin.db);
+    let admin = Arc::clone(admin);
+    let endpoint = endpoint.clone();
+
+    // Spawn our handler to be run asynchronously.
+    tokio::spawn(async move {
+        // Behind a trusted proxy, the client address comes in the PROXY protocol header
+        let addr = if behind_proxy {
+            match time::timeout(login_timeout, proxy::read_header(&mut stream)).await {
+                Ok(Ok(Some(client))) => PeerAddr::Ip(client),
+                Ok(Ok(None)) => addr,
+                Ok(Err(err)) => {
+                    debug!("Dropping connection from proxy {}: {}", addr, err);
+                    return;
+                },
+                Err(_) => {
+                    debug!("Timed out waiting for the PROXY header from {}", addr);
+                    return;
+                },
+            }
+        } else {
+            addr
+        };
+        if behind_proxy && is_banned(&admin, &addr).await {
+            return;
+        }
+
+        // Every log record of the connection carries its session id, peer address and, once
+        // logged in, username.
+        let session = Session::new(addr.to_string());
+        session.scope(async move {
+            // The TLS and WebSocket handshakes are part of the log in phase
+            let stream = match time::timeout(login_timeout, endpoint.handshake(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(err)) => {
+                    debug!("Handshake failed: {}", err);
+                    return;
+                },
+                Err(_) => {
+                    debug!("Timed out during the handshake");
+                    return;
+                },
+            };
+
+            if let Err(e) = process(world, data, admin, stream, 
+                                    login_timeout, login_slot, keepalive).await {
+                error!("User dropped with error, ERROR: {:?}", e);
+            }
+            drop(conn_slot);
+        }).await;
+    });
+}
+
+/// Returns true, recording it, if the client address is banned
+async fn is_banned(admin: &AdminContext, addr: &PeerAddr) -> bool {
+    let ip = match addr {
+        PeerAddr::Ip(addr) => addr.ip(),
+        PeerAddr::Unix(_) => return false,
+    };
+    match admin.bans.lock().await.check_ip(ip) {
+        Some(ban) => {
+            info!("Refused connection from banned address {}: {}", addr, ban.reason);
+            admin.audit.record("connection_refused", json::object!{
+                "source" => addr.to_string(), "reason" => ban.reason.clone(),
+            });
+            Metrics::inc(&METRICS.connections_refused);
+            true
+        },
+        None => false,
+    }
+}
+
+/// Validates the loaded configuration and its database file, then exits the process.
+/// The exit code is 0 if everything is correct.
+fn check_config(config_path: &str, config: &ServerConfig) -> ! {
+    for var in &config.unknown_env {
+        eprintln!("{}: ignoring {}, it does not match any setting", config_path, var);
+    }
+    let listeners = config.all_listeners();
+    if listeners.is_empty() && config.unix_socket.is_empty() {
+        eprintln!("{}: no listener configured", config_path);
+        process::exit(1);
+    }
+    for listener in &listeners {
+        if let Err(err) = listener.address.to_socket_addrs() {
+            eprintln!("{}: invalid listen address {}: {}", config_path, listener.address, err);
+            process::exit(1);
+        }
+        if let Err(err) = Endpoint::new(listener) {
+            eprintln!("{}: invalid listener {}: {}", config_path, listener.address, err);
+            process::exit(1);
+        }
+    }
+    if let Err(err) = config.check_keepalive() {
+        eprintln!("{}: {}", config_path, err);
+        process::exit(1);
+    }
+    if !config.backplane.redis_address.is_empty() && config.backplane.instance.is_empty() {
+        eprintln!("{}: the backplane needs an instance name", config_path);
+        process::exit(1);
+    }
+    if !config.federation.server_name.is_empty() {
+        if let Err(err) = federation::check_config(&config.federation) {
+            eprintln!("{}: invalid federation: {}", config_path, err);
+            process::exit(1);
+        }
+    }
+    if let Err(err) = DataBase::new(&config.database_file) {
+        eprintln!("{}: cannot load database {}: {}", config_path, config.database_file, err);
+        process::exit(1);
+    }
+    println!("{}: configuration and database are valid", config_path);
+    process::exit(0);
+}
+
+/// Reloads the configuration and the user database every time SIGHUP is received
+async fn reload_on_hangup(config_path: String,
+                          overrides: Overrides,
+                          config: Arc<Mutex<ServerConfig>>,
+                          db: Arc<Mutex<DataBase>>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(err) => {
+            error!("Cannot listen for SIGHUP, reloading disabled: {}", err);
+            return;
+        },
+    };
+
+    while hangup.recv().await.is_some() {
+        info!("SIGHUP received, reloading {}", config_path);
+        match reload(&config_path, &overrides, &config, &db).await {
+            Ok(restart) => {
+                info!("Configuration and database reloaded");
+                for setting in restart {
+                    warn!("Setting '{}' changed, restart the server to apply it", setting);
+                }
+            },
+            Err(err) => error!("Reload failed, keeping the old configuration: {}", err),
+        }
+    }
+}
+
+/// Counts as one unit of the given counter while alive. Used to keep track of pending log ins
+/// and open connections, the unit is released when the guard is dropped.
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    fn new(counter: Arc<AtomicUsize>) -> Slot {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Slot(counter)
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Heartbeat settings of a logged in session
+#[derive(Clone, Copy)]
+struct KeepAlive {
+    interval: Duration,     // Zero disables heartbeats
+    idle_timeout: Duration, // Zero disables it
+}
+
+impl KeepAlive {
+    /// Period of the checks of the session, None if there is nothing to check. Without
+    /// heartbeats the idle time is checked every `idle_timeout`.
+    fn period(&self) -> Option<Duration> {
+        [self.interval, self.idle_timeout].iter().copied().find(|d| *d > Duration::from_secs(0))
+    }
+}
+
+/// Runs a command sent by a user to the server. Except for public commands (like `info`), only
+/// server admins are allowed to run them. The output is sent back as messages from the server.
+async fn server_command(admin: &AdminContext, user: &mut Peer, name: &str, text: &str) {
+    let result = match AdminCommand::parse(text) {
+        Ok(command) if command.is_public() || admin.is_admin(name).await => {
+            admin.execute(name, command).await
+        },
+        Ok(_) => {
+            admin.audit.record("permission_denied", json::object!{ "command" => text });
+            Err("Permission denied, you are not a server admin".to_string())
+        },
+        Err(err) => Err(err),
+    };
+
+    match result {
+        Ok(lines) => send_server_lines(user, name, lines).await,
+        Err(err) => {
+            if let Err(err) = user.send_command(&Command::Err(err)).await {
+                debug!("Cannot send Err command to user {}: {}", name, err);
+            }
+        },
+    }
+}
+
+/// Sends the lines to the user as messages from the server
+async fn send_server_lines(user: &mut Peer, name: &str, lines: Vec<String>) {
+    for line in lines.iter().flat_map(|l| split_text(l)) {
+        let command = Command::Msg(SERVER_NAME.to_string(), name.to_string(), line);
+        if let Err(err) = user.send_command(&command).await {
+            debug!("Cannot send server message to user {}: {}", name, err);
+        }
+    }
+}
+
+/// Returns true if the error means the connection with the peer is lost
+fn is_disconnect(err: &io::Error) -> bool {
+    match err.kind() {
+        io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted |
+        io::ErrorKind::BrokenPipe | io::ErrorKind::TimedOut => true,
+        _ => false,
+    }
 }
 
 async fn process(shared_conn: Arc<Mutex<SharedConn>>,
                  db: Arc<Mutex<DataBase>>,
-                 stream: TcpStream,
-                 addr: SocketAddr) -> Result<(), io::Error> {
+                 admin: Arc<AdminContext>,
+                 stream: Box<dyn Connection>,
+                 login_timeout: Duration,
+                 login_slot: Slot,
+                 keepalive: KeepAlive) -> Result<(), io::Error> {
     
-    debug!("New connection from : {}", addr);
+    debug!("New connection");
+
+    // Sessions of the same user are told apart by the id of their connection
+    let session_id: SessionId = Session::current().map_or(0, |session| session.id);
 
     // Create a channel
     let (tx, rx) = mpsc::unbounded_channel(); 
 
     let mut user = Peer::new(stream, rx);
 
-    // Read the log in command from the user and parse to Command
-    let login_command = match user.read_command().await {
-        Ok(Some(login)) => login,
-        Ok(None) => {
+    // Read the log in command from the user and parse to Command.
+    // If the user does not log in on time, close the connection.
+    let login_command = match time::timeout(login_timeout, user.read_command()).await {
+        Ok(Ok(Some(login))) => login,
+        Ok(Ok(None)) => {
             debug!("Connection losed!");
             return Ok(());
         },
-        Err(e) => {
+        Ok(Err(e)) => {
             debug!("User login error: {}", e);
             return Ok(());
         },
+        Err(_) => {
+            debug!("Timed out before log in");
+            let _ = user.send_command(&Command::Err("Log in timeout".to_string())).await;
+            return Ok(());
+        },
     };
     // Check if the log in command is correct.
     // If the username is registered, check password.
     // Else, log in the user as anonymous user.
-    let name = match db.lock().await.check_log_in_credentials(login_command) {
+    let login_name = match &login_command {
+        Command::Usr(name, _) => name.clone(),
+        _ => String::new(),
+    };
+    let credentials = {
+        let bans = admin.bans.lock().await;
+        db.lock().await.check_log_in_credentials(login_command, &bans)
+    };
+    let name = match credentials {
             Ok(name) => {
                 // The crediantials where ok.
                 // Check if a client with the same user is 
                 // already loged in or register the user
-                if let Err(err) = shared_conn.lock().await.add(name.clone(), tx) {
+                let (max_sessions, takeover) = {
+                    let cfg = admin.config.lock().await;
+                    (cfg.max_sessions_per_user,
+                     cfg.session_takeover && db.lock().await.name_exists(&name))
+                };
+                let added = {
+                    let mut shared_conn = shared_conn.lock().await;
+                    let added = match shared_conn.add(name.clone(), session_id, tx.clone(), max_sessions) {
+                        // Registered users may take over their oldest session instead
+                        Err(err) if err.kind() == io::ErrorKind::AlreadyExists && takeover => {
+                            let reason = "Session taken over by a new log in";
+                            match shared_conn.take_over(&name, reason).await {
+                                Ok(ghost) => {
+                                    info!("User {} took over its session {}", name, ghost);
+                                    admin.audit.record("takeover", json::object!{
+                                        "user" => name.clone(), "old_session" => ghost,
+                                    });
+                                    shared_conn.add(name.clone(), session_id, tx, max_sessions)
+                                },
+                                Err(err) => Err(err),
+                            }
+                        },
+                        result => result,
+                    };
+                    // IP bans also close the sessions already logged in
+                    if let (Ok(()), Some(ip)) = (&added, Session::current().and_then(|s| s.ip())) {
+                        shared_conn.set_address(session_id, ip);
+                    }
+                    added
+                };
+                if let Err(err) = added {
                     // The user is already loged in... so suspicious
                     debug!("User {}, error: {}", name, err.to_string()); 
+                    admin.audit.record("login_failure", json::object!{
+                        "user" => name.clone(), "reason" => err.to_string(),
+                    });
                     let _ = user.send_command(&Command::Err(err.to_string())).await?;
                     return Err(io::Error::new(io::ErrorKind::AlreadyExists,
                                               "A user with the same credentials is already loged in"));
                 }
                 // Notify the user for successful log in
                 user.send_command(&Command::Ok).await?;
+                Metrics::inc(&METRICS.logins);
+                admin.audit.record("login", json::object!{
+                    "user" => name.clone(),
+                    "registered" => db.lock().await.name_exists(&name),
+                });
                 name
             },
             Err(err) => {
+                Metrics::inc(&METRICS.login_failures);
+                admin.audit.record("login_failure", json::object!{
+                    "user" => login_name, "reason" => err.to_string(),
+                });
                 let _ = user.send_command(&Command::Err(err.to_string())).await;
                 return Err(io::Error::new(io::ErrorKind::PermissionDenied, 
                                           format!("Login error: {}", err)));
             },
     };
 
-    debug!("User {} loged in", name);
+    // From now on the log records of the connection carry the username
+    Session::set_current_user(&name);
+
+    // The log in phase is over, free the pending slot
+    drop(login_slot);
+
+    // Send the message of the day
+    send_server_lines(&mut user, &name, admin.motd().await).await;
+
+    debug!("Logged in");
+
+    if let Some(period) = keepalive.period() {
+        user.set_heartbeat(period);
+    }
 
     while let Some(request) = user.next().await {
         match request {
             Ok(Message::Received(mesg)) => {
                 // Send the received message to the target user 
                 if let Err(err) = user.send_command(&mesg).await {
-                    debug!("User {} error sending message: {}", name, err);
+                    debug!("Error sending message: {}", err);
+                }
+            },
+            Ok(Message::Close(reason)) => {
+                // The server asked to end the session
+                debug!("Closing session of user {}: {}", name, reason);
+                let _ = user.send_command(&Command::Err(reason)).await;
+                break;
+            },
+            Ok(Message::Heartbeat) => {
+                // Drop the user if nothing was received for too long
+                let idle_timeout = keepalive.idle_timeout > Duration::from_secs(0);
+                if idle_timeout && user.idle_time() >= keepalive.idle_timeout {
+                    debug!("Idle for {:?}, dropping connection", user.idle_time());
+                    let _ = user.send_command(&Command::Err("Idle timeout".to_string())).await;
+                    break;
+                }
+                // Ping the user, the client is expected to answer with an OK command.
+                // A failing write means that the peer is gone.
+                if keepalive.interval == Duration::from_secs(0) {
+                    continue;
+                }
+                if let Err(err) = user.send_command(&Command::Ok).await {
+                    debug!("Heartbeat failed: {}", err);
+                    break;
                 }
             },
             Ok(Message::ToSend(mesg)) => {
@@ -151,10 +857,19 @@ async fn process(shared_conn: Arc<Mutex<SharedConn>>,
                 // normally its a message to forward to another user or group (MSG commad).
                 // If the message is not a MSG command, process the command.
                 match mesg {
+                    // Messages to the server are admin commands
+                    Command::Msg(_, target, text) if target == SERVER_NAME => {
+                        server_command(&admin, &mut user, &name, &text).await;
+                    },
                     Command::Msg(_,_,_) => {
                         // Send the message to the target 
-                        if let Err(err) = shared_conn.lock().await.send(mesg).await {
+                        if let Err(err) = shared_conn.lock().await.send(&name, mesg).await {
                             trace!("Error user {} when trying to send data: {}", name, err);
+                            if err.kind() == io::ErrorKind::PermissionDenied {
+                                admin.audit.record("permission_denied", json::object!{
+                                    "reason" => err.to_string(),
+                                });
+                            }
                             // Crate an error command
                             let command = Command::Err(
                                 format!("unable to send message: {}", err));
@@ -168,11 +883,11 @@ async fn process(shared_conn: Arc<Mutex<SharedConn>>,
                     Command::Join(join_name) => {
                         // Determine if the user wants to join another user or a group
                         if join_name.starts_with('#') {
-                            trace!("User {} wants to join group: {}", name, join_name);
+                            trace!("Wants to join group: {}", join_name);
                             
                             // If the group exists, join the group, else, create it
                             if let Err(err) = shared_conn.lock().await.join_group(&join_name, &name).await {
-                                debug!("User {} cannot join {}: {}", name, join_name, err);
+                                debug!("Cannot join {}: {}", join_name, err);
 
                                 // Send error to the user
                                 let command = Command::Err(
@@ -181,13 +896,9 @@ async fn process(shared_conn: Arc<Mutex<SharedConn>>,
                                     debug!("Cannot send Err command to user {}: {}",
                                               name, err);
                                 }
-                            } else {
-                                // The user successully joined the group, add the group name to the
-                                // list of groups of the user
-                                user.groups.push(join_name.clone());
                             }
                         } else {
-                            trace!("User {} wants to join user {}", name, join_name);
+                            trace!("Wants to join user {}", join_name);
                         }
                     },
                     Command::Leave(target) => {
@@ -196,14 +907,14 @@ async fn process(shared_conn: Arc<Mutex<SharedConn>>,
                             if let Err(err) = shared_conn.lock().await.leave_group(&name, &target).await {
                                 warn!("Could not remove user {} from group {}: {}", name, target, err);
                             } else {
-                                trace!("User {} left group {}", name, target);
+                                trace!("Left group {}", target);
                             }
                         }
                     },
                     Command::ListUsr(gname, _, _) => {
                         // Check if the gname is really a group name (groups starts with #)
                         if !gname.starts_with('#') {
-                            debug!("User {} trying to list a non group chat: '{}'", name, gname);
+                            debug!("Trying to list a non group chat: '{}'", gname);
                             // Send error to the user
                             let cmd = Command::Err(
                                 format!("Trying to list a non group chat: '{}'", gname));
@@ -213,7 +924,7 @@ async fn process(shared_conn: Arc<Mutex<SharedConn>>,
                             }
                         }
     
-                        trace!("User {} requests listing group: {}", name, gname);
+                        trace!("Requests listing group: {}", gname);
                         if let Ok(usrs_list) = shared_conn.lock().await.list_group(&gname) {
                             for set in usrs_list {
                                 let cmd = Command::ListUsr(gname.clone(), ListUsrOperation::Add, set);
@@ -227,9 +938,11 @@ async fn process(shared_conn: Arc<Mutex<SharedConn>>,
                             debug!("cannot list group {}", gname);
                         }
                     },
+                    // Heartbeat reply, receiving it is enough to refresh the idle time
+                    Command::Ok => trace!("Answered heartbeat"),
                     // Notify that a non valid command is sent
                     _ => {
-                        trace!("User {} invaid command received", name);
+                        trace!("Invaid command received");
                         user.send_command(
                             &Command::Err(
                                 "Unable to send non MSG command".to_string()
@@ -240,25 +953,20 @@ async fn process(shared_conn: Arc<Mutex<SharedConn>>,
             },
 
             Err(err) => {
-                debug!("Error, user {}: {}", name, err);
+                debug!("Error: {}", err);
+                if is_disconnect(&err) {
+                    break;
+                }
             },
         }
     }
 
     // Delete the user from Shared and for every group it's member of
-    debug!("User {} loged out", name);
+    debug!("Logged out");
 
-    // Delete user for all the groups is in
-    for group in user.groups {
-        if let Err(err) = shared_conn.lock().await.leave_group(&name, &group).await {
-            warn!("Could not remove user {} from group {}: {}", name, group, err);
-        } else {
-            trace!("User {} left group {}", name, group);
-        }
-    }
-    // Delete user from shared 
-    if let Err(err) = shared_conn.lock().await.remove(&name) {
-        debug!("Error, user {}: {}", name, err); 
+    // Delete the session from shared, with its last session the user leaves all its groups
+    if let Err(err) = shared_conn.lock().await.remove(&name, session_id).await {
+        debug!("Error: {}", err); 
     }
 
     
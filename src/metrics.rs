@@ -0,0 +1,155 @@
+use std::fmt::Write;
+use std::io;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::admin::AdminContext;
+
+/// Upper bounds, in seconds, of the buckets of the queue latency histogram
+const LATENCY_BUCKETS: [f64; 7] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.1, 1.0];
+
+/// Error kinds tracked separately for failed sends, the rest are counted as `other`
+const ERROR_KINDS: [(io::ErrorKind, &str); 4] = [
+    (io::ErrorKind::NotFound, "not_found"),
+    (io::ErrorKind::PermissionDenied, "permission_denied"),
+    (io::ErrorKind::BrokenPipe, "broken_pipe"),
+    (io::ErrorKind::InvalidInput, "invalid_input"),
+];
+
+/// Server wide counters
+pub struct Metrics {
+    pub connections: AtomicU64,         // Accepted connections
+    pub connections_refused: AtomicU64, // Dropped by bans or the pending log in limit
+    pub logins: AtomicU64,
+    pub login_failures: AtomicU64,
+    pub messages_direct: AtomicU64,
+    pub messages_group: AtomicU64,
+    send_errors: [AtomicU64; 5],        // One per ERROR_KINDS, the last one is `other`
+    queued: AtomicI64,                  // Commands waiting in the users' queues
+    latency_buckets: [AtomicU64; 7],    // One per LATENCY_BUCKETS
+    latency_count: AtomicU64,
+    latency_sum_us: AtomicU64,          // Microseconds
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+
+    const fn new() -> Metrics {
+        Metrics {
+            connections: AtomicU64::new(0),
+            connections_refused: AtomicU64::new(0),
+            logins: AtomicU64::new(0),
+            login_failures: AtomicU64::new(0),
+            messages_direct: AtomicU64::new(0),
+            messages_group: AtomicU64::new(0),
+            send_errors: [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0),
+                          AtomicU64::new(0), AtomicU64::new(0)],
+            queued: AtomicI64::new(0),
+            latency_buckets: [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0),
+                              AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0),
+                              AtomicU64::new(0)],
+            latency_count: AtomicU64::new(0),
+            latency_sum_us: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a failed message send
+    pub fn send_error(&self, kind: io::ErrorKind) {
+        let index = ERROR_KINDS.iter().position(|(k, _)| *k == kind).unwrap_or(ERROR_KINDS.len());
+        Metrics::inc(&self.send_errors[index]);
+    }
+
+    /// A command was queued to a user
+    pub fn queued(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A command left a user's queue after waiting for `latency`
+    pub fn dequeued(&self, latency: Duration) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+
+        let secs = latency.as_secs_f64();
+        for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+            if secs <= *bound {
+                Metrics::inc(&self.latency_buckets[i]);
+            }
+        }
+        Metrics::inc(&self.latency_count);
+        self.latency_sum_us.fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// A queued command was dropped without being delivered
+    pub fn discarded(&self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Appends a metric in the Prometheus text format
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Renders all the metrics in the Prometheus text exposition format
+pub async fn render(ctx: &AdminContext) -> String {
+    let m = &METRICS;
+    let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+    let mut out = String::new();
+
+    let (users, groups) = {
+        let shared_conn = ctx.shared_conn.lock().await;
+        (shared_conn.list_users().len(), shared_conn.list_groups().len())
+    };
+
+    metric(&mut out, "ostrich_uptime_seconds", "gauge", "Seconds since the server started",
+           ctx.started.elapsed().as_secs());
+    metric(&mut out, "ostrich_connections_total", "counter", "Accepted connections",
+           load(&m.connections));
+    metric(&mut out, "ostrich_connections_refused_total", "counter",
+           "Connections dropped because of bans or the pending log in limit",
+           load(&m.connections_refused));
+    metric(&mut out, "ostrich_connections_open", "gauge", "Open connections",
+           ctx.connections.load(Ordering::SeqCst));
+    metric(&mut out, "ostrich_pending_logins", "gauge", "Connections waiting to log in",
+           ctx.pending.load(Ordering::SeqCst));
+    metric(&mut out, "ostrich_users", "gauge", "Logged in users", users);
+    metric(&mut out, "ostrich_groups", "gauge", "Existing groups", groups);
+    metric(&mut out, "ostrich_logins_total", "counter", "Successful log ins", load(&m.logins));
+    metric(&mut out, "ostrich_login_failures_total", "counter", "Failed log ins",
+           load(&m.login_failures));
+
+    let _ = writeln!(out, "# HELP ostrich_messages_total Messages routed to their target");
+    let _ = writeln!(out, "# TYPE ostrich_messages_total counter");
+    let _ = writeln!(out, "ostrich_messages_total{{type=\"direct\"}} {}", load(&m.messages_direct));
+    let _ = writeln!(out, "ostrich_messages_total{{type=\"group\"}} {}", load(&m.messages_group));
+
+    let _ = writeln!(out, "# HELP ostrich_send_errors_total Messages that could not be routed");
+    let _ = writeln!(out, "# TYPE ostrich_send_errors_total counter");
+    let kinds = ERROR_KINDS.iter().map(|(_, name)| *name).chain(std::iter::once("other"));
+    for (kind, counter) in kinds.zip(m.send_errors.iter()) {
+        let _ = writeln!(out, "ostrich_send_errors_total{{kind=\"{}\"}} {}", kind, load(counter));
+    }
+
+    metric(&mut out, "ostrich_queued_commands", "gauge",
+           "Commands waiting in the users' queues", m.queued.load(Ordering::Relaxed).max(0));
+
+    let _ = writeln!(out, "# HELP ostrich_queue_latency_seconds Time commands wait in the users' queues");
+    let _ = writeln!(out, "# TYPE ostrich_queue_latency_seconds histogram");
+    for (bound, counter) in LATENCY_BUCKETS.iter().zip(m.latency_buckets.iter()) {
+        let _ = writeln!(out, "ostrich_queue_latency_seconds_bucket{{le=\"{}\"}} {}",
+                         bound, load(counter));
+    }
+    let count = load(&m.latency_count);
+    let _ = writeln!(out, "ostrich_queue_latency_seconds_bucket{{le=\"+Inf\"}} {}", count);
+    let _ = writeln!(out, "ostrich_queue_latency_seconds_sum {}",
+                     load(&m.latency_sum_us) as f64 / 1_000_000.0);
+    let _ = writeln!(out, "ostrich_queue_latency_seconds_count {}", count);
+
+    out
+}
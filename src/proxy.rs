@@ -0,0 +1,242 @@
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+use crate::ban::{cidr_contains, BanTarget};
+
+/// Signature that starts every PROXY protocol v2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Max length of a v1 header, including the final CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// Networks of the proxies allowed to send PROXY protocol headers
+#[derive(Debug, Clone)]
+pub struct TrustedProxies(Vec<(IpAddr, u8)>); // Network address, prefix length
+
+impl TrustedProxies {
+
+    /// Parses a list of addresses (`10.0.0.1`) and networks (`10.0.0.0/8`)
+    pub fn parse(list: &[String]) -> Result<TrustedProxies, io::Error> {
+        let mut networks = Vec::new();
+        for entry in list {
+            let network = match BanTarget::from_str(entry) {
+                Ok(BanTarget::Ip(ip)) => (ip, if ip.is_ipv4() { 32 } else { 128 }),
+                Ok(BanTarget::Cidr(net, prefix)) => (net, prefix),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                               format!("invalid trusted proxy '{}'", entry))),
+            };
+            networks.push(network);
+        }
+        Ok(TrustedProxies(networks))
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|(net, prefix)| cidr_contains(*net, *prefix, ip))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid PROXY header: {}", msg))
+}
+
+/// Reads a PROXY protocol v1 or v2 header from the start of the stream, leaving the stream at
+/// the first byte after it. Returns the address of the client, or None if the proxy does not
+/// report it (`UNKNOWN` or `LOCAL` connections, e.g. health checks).
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>, io::Error> {
+    let mut start = [0u8; 5];
+    stream.read_exact(&mut start).await?;
+
+    if &start == b"PROXY" {
+        // Read the rest of the line, byte by byte so nothing after the header is consumed
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(invalid("v1 header too long"));
+            }
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).await?;
+            line.push(byte[0]);
+        }
+        let line = String::from_utf8(line).map_err(|_| invalid("v1 header is not text"))?;
+        parse_v1(line.trim_end())
+
+    } else if start == V2_SIGNATURE[..5] {
+        let mut rest = [0u8; 11];
+        stream.read_exact(&mut rest).await?;
+        if rest[..7] != V2_SIGNATURE[5..] {
+            return Err(invalid("bad v2 signature"));
+        }
+        let version_command = rest[7];
+        let family = rest[8];
+        let len = u16::from_be_bytes([rest[9], rest[10]]) as usize;
+
+        let mut addresses = vec![0u8; len];
+        stream.read_exact(&mut addresses).await?;
+        parse_v2(version_command, family, &addresses)
+
+    } else {
+        Err(invalid("missing header"))
+    }
+}
+
+/// Parses `PROXY TCP4 <src> <dst> <src port> <dst port>`
+fn parse_v1(line: &str) -> Result<Option<SocketAddr>, io::Error> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    let ipv4 = match fields.get(1) {
+        Some(&"UNKNOWN") => return Ok(None),
+        Some(&"TCP4") if fields.len() == 6 => true,
+        Some(&"TCP6") if fields.len() == 6 => false,
+        _ => return Err(invalid("unsupported v1 header")),
+    };
+    let ip = fields[2].parse::<IpAddr>().map_err(|_| invalid("bad v1 source address"))?;
+    let destination = fields[3].parse::<IpAddr>().map_err(|_| invalid("bad v1 destination address"))?;
+    if ip.is_ipv4() != ipv4 || destination.is_ipv4() != ipv4 {
+        return Err(invalid("v1 addresses do not match the protocol"));
+    }
+    let port = fields[4].parse::<u16>().map_err(|_| invalid("bad v1 source port"))?;
+    fields[5].parse::<u16>().map_err(|_| invalid("bad v1 destination port"))?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> Result<Option<SocketAddr>, io::Error> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported v2 version"));
+    }
+    match version_command & 0x0f {
+        0x0 => return Ok(None), // LOCAL, sent by the proxy itself
+        0x1 => (),              // PROXY
+        _ => return Err(invalid("unsupported v2 command")),
+    }
+
+    let port = |i: usize| u16::from_be_bytes([addresses[i], addresses[i + 1]]);
+    match family {
+        // TCP over IPv4: source, destination, source port, destination port
+        0x11 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port(8))))
+        },
+        // TCP over IPv6
+        0x21 if addresses.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port(32))))
+        },
+        0x11 | 0x21 => Err(invalid("v2 addresses too short")),
+        // UDP, Unix sockets or unspecified, the client address is not usable
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> Option<SocketAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn v1_headers() {
+        assert_eq!(parse_v1("PROXY TCP4 192.0.2.1 198.51.100.1 5000 9999").unwrap(),
+                   addr("192.0.2.1:5000"));
+        assert_eq!(parse_v1("PROXY TCP6 2001:db8::1 2001:db8::2 5000 9999").unwrap(),
+                   addr("[2001:db8::1]:5000"));
+        assert_eq!(parse_v1("PROXY UNKNOWN").unwrap(), None);
+        assert_eq!(parse_v1("PROXY UNKNOWN 192.0.2.1 198.51.100.1 5000 9999").unwrap(), None);
+
+        let malformed = [
+            "PROXY",
+            "PROXY TCP4",
+            "PROXY UDP4 192.0.2.1 198.51.100.1 5000 9999",
+            "PROXY TCP4 192.0.2.1 198.51.100.1 5000",
+            "PROXY TCP4 192.0.2.1 198.51.100.1 5000 9999 1",
+            "PROXY TCP4  192.0.2.1 198.51.100.1 5000 9999",
+            "PROXY TCP4 192.0.2 198.51.100.1 5000 9999",
+            "PROXY TCP4 192.0.2.1 198.51.100 5000 9999",
+            "PROXY TCP4 192.0.2.1 198.51.100.1 65536 9999",
+            "PROXY TCP4 192.0.2.1 198.51.100.1 5000 -1",
+            "PROXY TCP4 2001:db8::1 2001:db8::2 5000 9999",
+            "PROXY TCP6 192.0.2.1 198.51.100.1 5000 9999",
+        ];
+        for line in malformed.iter() {
+            let err = parse_v1(line).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", line);
+        }
+    }
+
+    #[test]
+    fn v2_headers() {
+        let v4 = [192, 0, 2, 1, 198, 51, 100, 1, 0x13, 0x88, 0x27, 0x0f];
+        assert_eq!(parse_v2(0x21, 0x11, &v4).unwrap(), addr("192.0.2.1:5000"));
+        // Extra bytes are TLVs
+        assert_eq!(parse_v2(0x21, 0x11, &[&v4[..], &[1, 2, 3]].concat()).unwrap(),
+                   addr("192.0.2.1:5000"));
+
+        let mut v6 = vec![0u8; 36];
+        v6[0] = 0x20;
+        v6[1] = 0x01;
+        v6[15] = 1;
+        v6[32] = 0x13;
+        v6[33] = 0x88;
+        assert_eq!(parse_v2(0x21, 0x21, &v6).unwrap(), addr("[2001::1]:5000"));
+
+        // LOCAL connections and families without a client address
+        assert_eq!(parse_v2(0x20, 0x11, &v4).unwrap(), None);
+        assert_eq!(parse_v2(0x21, 0x12, &v4).unwrap(), None);
+        assert_eq!(parse_v2(0x21, 0x00, &[]).unwrap(), None);
+
+        let malformed: [(u8, u8, &[u8]); 5] = [
+            (0x11, 0x11, &v4),
+            (0x22, 0x11, &v4),
+            (0x21, 0x11, &v4[..11]),
+            (0x21, 0x21, &v6[..35]),
+            (0x21, 0x21, &v4),
+        ];
+        for (version_command, family, addresses) in malformed.iter() {
+            let err = parse_v2(*version_command, *family, addresses).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[tokio::test]
+    async fn headers_are_read_up_to_their_end() {
+        let mut stream: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 5000 9999\r\nLOGIN";
+        assert_eq!(read_header(&mut stream).await.unwrap(), addr("192.0.2.1:5000"));
+        assert_eq!(stream, b"LOGIN");
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0, 12, 192, 0, 2, 1, 198, 51, 100, 1, 0x13, 0x88, 0x27, 0x0f]);
+        header.extend_from_slice(b"LOGIN");
+        let mut stream = &header[..];
+        assert_eq!(read_header(&mut stream).await.unwrap(), addr("192.0.2.1:5000"));
+        assert_eq!(stream, b"LOGIN");
+
+        let long = format!("PROXY TCP4 {}\r\n", "1".repeat(V1_MAX_LEN));
+        let err = read_header(&mut long.as_bytes()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = read_header(&mut &b"LOGIN alice"[..]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let mut bad = V2_SIGNATURE.to_vec();
+        bad[8] = b'X';
+        bad.extend_from_slice(&[0x21, 0x11, 0, 0]);
+        let err = read_header(&mut &bad[..]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn trusted_proxies() {
+        let proxies = TrustedProxies::parse(&["10.0.0.1".to_string(),
+                                              "::ffff:192.168.0.0/112".to_string()]).unwrap();
+        assert!(proxies.contains("10.0.0.1".parse().unwrap()));
+        assert!(proxies.contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(proxies.contains("192.168.5.5".parse().unwrap()));
+        assert!(!proxies.contains("10.0.0.2".parse().unwrap()));
+        assert!(TrustedProxies::parse(&["alice".to_string()]).is_err());
+    }
+}
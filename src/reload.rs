@@ -0,0 +1,36 @@
+use tokio::sync::Mutex;
+
+use std::error::Error;
+
+use crate::DataBase;
+use crate::config::{Config, Overrides};
+use crate::logging::Logger;
+
+/// Re-reads the configuration file and rebuilds the user database in place.
+/// `overrides` are applied again on top of the new configuration.
+/// Nothing is changed if the new configuration or database cannot be loaded.
+/// Returns the names of the changed settings that only take effect after a restart, those keep
+/// their current values until then.
+pub async fn reload(config_path: &str,
+                    overrides: &Overrides,
+                    config: &Mutex<Config>,
+                    db: &Mutex<DataBase>) -> Result<Vec<&'static str>, Box<dyn Error>> {
+
+    let mut new_config = Config::new(config_path)?;
+    overrides.apply(&mut new_config);
+    new_config.check_keepalive()?;
+    for var in &new_config.unknown_env {
+        warn!("Ignoring {}, it does not match any setting", var);
+    }
+    let new_db = DataBase::new(&new_config.database_file)?;
+
+    let mut config = config.lock().await;
+    let restart = config.restart_required(&new_config);
+    new_config.keep_restart_only(&config);
+    Logger::configure(&new_config.logging);
+
+    *db.lock().await = new_db;
+    *config = new_config;
+
+    Ok(restart)
+}
@@ -0,0 +1,82 @@
+use tokio::net::{TcpListener, UnixListener};
+
+use std::env;
+use std::io;
+use std::net;
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::{self as unix, UnixDatagram};
+use std::process;
+
+use crate::listener::Listener;
+
+/// First file descriptor passed by systemd
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Returns the listening sockets passed by systemd socket activation (`LISTEN_FDS`), or an
+/// empty list if the server was not socket activated. The activation variables are removed
+/// from the environment so that they are not inherited.
+pub fn listen_fds() -> Result<Vec<Listener>, io::Error> {
+    let pid = env::var("LISTEN_PID").ok().and_then(|p| p.parse::<u32>().ok());
+    let count = env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<RawFd>().ok());
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    // The sockets are meant for this process only
+    let count = match (pid, count) {
+        (Some(pid), Some(count)) if pid == process::id() => count,
+        _ => return Ok(Vec::new()),
+    };
+
+    let mut listeners = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        listeners.push(from_fd(fd)?);
+    }
+    Ok(listeners)
+}
+
+/// Takes ownership of a listening TCP or Unix domain socket
+fn from_fd(fd: RawFd) -> Result<Listener, io::Error> {
+    // The address family tells the kind of socket, local_addr fails on Unix sockets
+    let tcp = unsafe { net::TcpListener::from_raw_fd(fd) };
+    if tcp.local_addr().is_ok() {
+        tcp.set_nonblocking(true)?;
+        return Ok(Listener::Tcp(TcpListener::from_std(tcp)?));
+    }
+    let fd = tcp.into_raw_fd();
+
+    let unix = unsafe { unix::UnixListener::from_raw_fd(fd) };
+    let path = match unix.local_addr() {
+        Ok(addr) => match addr.as_pathname() {
+            Some(path) => path.display().to_string(),
+            None => format!("fd{}", fd),
+        },
+        Err(err) => {
+            // Not a socket we can accept from, leave the descriptor to systemd
+            let _ = unix.into_raw_fd();
+            return Err(io::Error::new(err.kind(),
+                                      format!("passed file descriptor {} is not a TCP or Unix \
+                                               listening socket: {}", fd, err)));
+        },
+    };
+    unix.set_nonblocking(true)?;
+    Ok(Listener::Unix(UnixListener::from_std(unix)?, path))
+}
+
+/// Sends a state notification (e.g. `READY=1`) to the systemd service manager. Does nothing
+/// if the server is not run by systemd with notifications enabled (`Type=notify`).
+pub fn notify(state: &str) {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) if !path.is_empty() => path,
+        _ => return,
+    };
+    if path.starts_with('@') {
+        warn!("Abstract notification socket {} is not supported, cannot notify systemd", path);
+        return;
+    }
+
+    let result = UnixDatagram::unbound().and_then(|socket| socket.send_to(state.as_bytes(), &path));
+    if let Err(err) = result {
+        warn!("Cannot notify systemd of {}: {}", state, err);
+    }
+}
@@ -0,0 +1,442 @@
+use ostrich_core::*;
+use json::JsonValue;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use core::task::{Poll, Context};
+use std::io;
+use std::pin::Pin;
+
+/// Max size of the handshake request head
+const MAX_HEAD: usize = 8 * 1024;
+/// Max size of a message, bigger messages close the connection
+const MAX_MESSAGE: usize = 64 * 1024;
+/// Appended to the client key to compute the accept key of the handshake
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Path of the WebSocket endpoint that carries commands as JSON text messages.
+/// Any other path carries the raw `ostrich-core` packets in binary messages.
+pub const JSON_PATH: &str = "/json";
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+/// Close status code of a connection failed because the client broke the protocol
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+/// Close status code of a connection failed because the client sent a too big message
+const CLOSE_TOO_BIG: u16 = 1009;
+
+/// How commands are carried in the WebSocket messages
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Binary, // A raw `ostrich-core` packet per binary message
+    Json,   // A JSON object per text message, see `command_to_json`
+}
+
+/// Server side of a WebSocket connection. Reads yield one `ostrich-core` packet per message and
+/// every write must be a whole packet, so a `Peer` can use it like a TCP socket.
+pub struct WsStream<S> {
+    inner: S,
+    mode: Mode,
+    rbuf: Vec<u8>,       // Data read from the inner stream not yet parsed
+    message: Vec<u8>,    // Payload of the fragmented message being received
+    opcode: u8,          // Opcode of the fragmented message being received
+    incoming: Vec<u8>,   // Packet ready to be read
+    wbuf: Vec<u8>,       // Frames not yet written to the inner stream
+    closed: bool,        // A close frame was received
+}
+
+/// Performs the server side of the WebSocket handshake over `stream`
+pub async fn accept<S>(mut stream: S) -> Result<WsStream<S>, io::Error>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    // Read the request head
+    let mut head = Vec::new();
+    let mut chunk = [0u8; 1024];
+    let end = loop {
+        if let Some(i) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+        if head.len() >= MAX_HEAD {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "handshake request too big"));
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "connection closed during the handshake"));
+        }
+        head.extend_from_slice(&chunk[..n]);
+    };
+
+    let request = String::from_utf8_lossy(&head[..end]).to_string();
+    let (path, key) = match parse_request(&request) {
+        Ok(r) => r,
+        Err(err) => {
+            let response = format!("HTTP/1.1 400 Bad Request\r\n\
+                                    Content-Length: {}\r\n\
+                                    Connection: close\r\n\r\n{}",
+                                   err.len(), err);
+            let _ = stream.write_all(response.as_bytes()).await;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+        },
+    };
+
+    let accept_key = base64::encode(Sha1::digest(format!("{}{}", key, GUID).as_bytes()));
+    let response = format!("HTTP/1.1 101 Switching Protocols\r\n\
+                            Upgrade: websocket\r\n\
+                            Connection: Upgrade\r\n\
+                            Sec-WebSocket-Accept: {}\r\n\r\n",
+                           accept_key);
+    stream.write_all(response.as_bytes()).await?;
+
+    let mode = if path == JSON_PATH { Mode::Json } else { Mode::Binary };
+    trace!("WebSocket handshake completed, path {}, {:?} mode", path, mode);
+
+    Ok(WsStream {
+        inner: stream,
+        mode,
+        rbuf: head[end..].to_vec(),
+        message: Vec::new(),
+        opcode: OP_CONTINUATION,
+        incoming: Vec::new(),
+        wbuf: Vec::new(),
+        closed: false,
+    })
+}
+
+/// Checks the upgrade request, returning its path and the client key
+fn parse_request(request: &str) -> Result<(String, String), String> {
+    let mut lines = request.lines();
+    let mut request_line = lines.next().unwrap_or("").split_whitespace();
+    if request_line.next() != Some("GET") {
+        return Err("only GET requests can be upgraded".to_string());
+    }
+    let path = request_line.next().unwrap_or("/").to_string();
+
+    let mut upgrade = false;
+    let mut version = false;
+    let mut key = None;
+    for line in lines {
+        let (name, value) = match line.find(':') {
+            Some(i) => (line[..i].trim().to_lowercase(), line[i+1..].trim()),
+            None => continue,
+        };
+        match name.as_str() {
+            "upgrade" => upgrade = value.eq_ignore_ascii_case("websocket"),
+            "sec-websocket-version" => version = value == "13",
+            "sec-websocket-key" => key = Some(value.to_string()),
+            _ => (),
+        }
+    }
+
+    if !upgrade {
+        return Err("not a WebSocket upgrade request".to_string());
+    }
+    if !version {
+        return Err("unsupported WebSocket version, only 13 is supported".to_string());
+    }
+    match key {
+        Some(key) => Ok((path, key)),
+        None => Err("missing Sec-WebSocket-Key".to_string()),
+    }
+}
+
+/// Encodes an unmasked frame, as sent by servers
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode); // FIN
+    if payload.len() < 126 {
+        frame.push(payload.len() as u8);
+    } else if payload.len() <= u16::MAX as usize {
+        frame.push(126);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+        frame.push(127);
+        frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// A decoded frame: FIN bit, opcode, unmasked payload and number of bytes used
+type Frame = (bool, u8, Vec<u8>, usize);
+
+/// Decodes a frame from the start of `buf`. Returns the FIN bit, the opcode, the unmasked
+/// payload and the number of bytes used, or None if the frame is not complete yet. Protocol
+/// errors are returned as the status code to close the connection with and a reason.
+fn decode_frame(buf: &[u8]) -> Result<Option<Frame>, (u16, String)> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = buf[0] & 0x0f;
+    if buf[1] & 0x80 == 0 {
+        return Err((CLOSE_PROTOCOL_ERROR, "client frames must be masked".to_string()));
+    }
+
+    let (len, mut pos) = match buf[1] & 0x7f {
+        126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+        127 if buf.len() >= 10 => {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&buf[2..10]);
+            (u64::from_be_bytes(bytes), 10)
+        },
+        126 | 127 => return Ok(None),
+        n => (n as u64, 2),
+    };
+    if len > MAX_MESSAGE as u64 {
+        return Err((CLOSE_TOO_BIG, "WebSocket message too big".to_string()));
+    }
+    if !matches!(opcode, OP_CONTINUATION | OP_TEXT | OP_BINARY | OP_CLOSE | OP_PING | OP_PONG) {
+        return Err((CLOSE_PROTOCOL_ERROR, format!("unknown WebSocket opcode {}", opcode)));
+    }
+    let len = len as usize;
+    if buf.len() < pos + 4 + len {
+        return Ok(None);
+    }
+
+    let mut mask = [0u8; 4];
+    mask.copy_from_slice(&buf[pos..pos + 4]);
+    pos += 4;
+    let payload = buf[pos..pos + len].iter()
+        .enumerate()
+        .map(|(i, b)| b ^ mask[i % 4])
+        .collect();
+    Ok(Some((fin, opcode, payload, pos + len)))
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WsStream<S> {
+
+    /// Writes the buffered frames to the inner stream
+    fn poll_write_buffer(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        while !self.wbuf.is_empty() {
+            let n = match Pin::new(&mut self.inner).poll_write(cx, &self.wbuf) {
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            };
+            if n == 0 {
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::WriteZero,
+                                                      "cannot write WebSocket frame")));
+            }
+            self.wbuf.drain(..n);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Fails the connection after a protocol error: the data still buffered is dropped, a close
+    /// frame with the given status code is sent and the stream ends. Returning the error instead
+    /// would leave the stream in the same state, failing every read.
+    fn fail(&mut self, cx: &mut Context<'_>, code: u16, reason: &str) -> Poll<Result<usize, io::Error>> {
+        debug!("Closing WebSocket connection: {}", reason);
+        self.rbuf.clear();
+        self.message.clear();
+        self.wbuf.extend(encode_frame(OP_CLOSE, &code.to_be_bytes()));
+        self.closed = true;
+        let _ = self.poll_write_buffer(cx);
+        Poll::Ready(Ok(0))
+    }
+
+    /// Handles a complete message, turning data messages into the next packet to read
+    fn handle_message(&mut self, opcode: u8, payload: Vec<u8>) -> Result<(), io::Error> {
+        match opcode {
+            OP_BINARY => self.incoming = payload,
+            OP_TEXT => {
+                let text = String::from_utf8(payload)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                let value = json::parse(&text)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                let command = command_from_json(&value)?;
+                self.incoming = RawMessage::to_raw(&command)?.to_vec();
+            },
+            OP_PING => self.wbuf.extend(encode_frame(OP_PONG, &payload)),
+            OP_PONG => (),
+            OP_CLOSE => {
+                // Answer with the status code of the client
+                let code = if payload.len() >= 2 { &payload[..2] } else { &[] };
+                self.wbuf.extend(encode_frame(OP_CLOSE, code));
+                self.closed = true;
+            },
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                           format!("unknown WebSocket opcode {}", opcode))),
+        }
+        Ok(())
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+
+    fn poll_read(mut self: Pin<&mut Self>,
+                 cx: &mut Context<'_>,
+                 buf: &mut [u8]) -> Poll<Result<usize, io::Error>> {
+        let this = &mut *self;
+        loop {
+            // Send pending control frames (pongs, close), without waiting for them
+            if let Poll::Ready(Err(err)) = this.poll_write_buffer(cx) {
+                return Poll::Ready(Err(err));
+            }
+
+            if !this.incoming.is_empty() {
+                let n = buf.len().min(this.incoming.len());
+                buf[..n].copy_from_slice(&this.incoming[..n]);
+                this.incoming.drain(..n);
+                return Poll::Ready(Ok(n));
+            }
+            if this.closed {
+                return Poll::Ready(Ok(0));
+            }
+
+            // Parse the next frame, reading more data if it is not complete
+            let frame = match decode_frame(&this.rbuf) {
+                Ok(frame) => frame,
+                Err((code, reason)) => return this.fail(cx, code, &reason),
+            };
+            match frame {
+                Some((fin, opcode, payload, used)) => {
+                    this.rbuf.drain(..used);
+                    if opcode >= OP_CLOSE {
+                        // Control frames may come between the fragments of a message
+                        this.handle_message(opcode, payload)?;
+                        continue;
+                    }
+                    if opcode != OP_CONTINUATION {
+                        this.opcode = opcode;
+                        this.message.clear();
+                    }
+                    this.message.extend(payload);
+                    if this.message.len() > MAX_MESSAGE {
+                        return this.fail(cx, CLOSE_TOO_BIG, "WebSocket message too big");
+                    }
+                    if fin {
+                        let message = std::mem::replace(&mut this.message, Vec::new());
+                        this.handle_message(this.opcode, message)?;
+                    }
+                },
+                None => {
+                    let mut chunk = [0u8; 4096];
+                    let n = match Pin::new(&mut this.inner).poll_read(cx, &mut chunk) {
+                        Poll::Ready(Ok(n)) => n,
+                        Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                        Poll::Pending => return Poll::Pending,
+                    };
+                    if n == 0 {
+                        return Poll::Ready(Ok(0));
+                    }
+                    this.rbuf.extend_from_slice(&chunk[..n]);
+                },
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+
+    /// Sends `buf`, a whole `ostrich-core` packet, as a message
+    fn poll_write(mut self: Pin<&mut Self>,
+                  cx: &mut Context<'_>,
+                  buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+        let this = &mut *self;
+        match this.poll_write_buffer(cx) {
+            Poll::Ready(Ok(())) => (),
+            other => return other.map(|r| r.map(|_| 0)),
+        }
+
+        let frame = match this.mode {
+            Mode::Binary => encode_frame(OP_BINARY, buf),
+            Mode::Json => {
+                let command = RawMessage::from_raw(buf)?;
+                encode_frame(OP_TEXT, command_to_json(&command).dump().as_bytes())
+            },
+        };
+        this.wbuf.extend(frame);
+
+        // The packet is buffered, the frame is written now or on the next flush
+        if let Poll::Ready(Err(err)) = this.poll_write_buffer(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        match self.poll_write_buffer(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.inner).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        match self.poll_write_buffer(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.inner).poll_shutdown(cx),
+            other => other,
+        }
+    }
+}
+
+/// Maps a command to the JSON object sent to WebSocket clients in JSON mode:
+///
+/// | Command                      | JSON                                                    |
+/// |------------------------------|---------------------------------------------------------|
+/// | `Usr(name, password)`        | `{"cmd": "usr", "name": .., "password": ..}`            |
+/// | `Msg(sender, target, text)`  | `{"cmd": "msg", "sender": .., "target": .., "text": ..}`|
+/// | `Join(target)`               | `{"cmd": "join", "target": ..}`                         |
+/// | `Leave(target)`              | `{"cmd": "leave", "target": ..}`                        |
+/// | `ListUsr(group, op, users)`  | `{"cmd": "listusr", "group": .., "operation": "add" or "remove", "users": ..}` |
+/// | `Err(text)`                  | `{"cmd": "err", "text": ..}`                            |
+/// | `Ok`                         | `{"cmd": "ok"}`                                         |
+pub fn command_to_json(command: &Command) -> JsonValue {
+    match command {
+        Command::Usr(name, password) => json::object!{
+            "cmd" => "usr", "name" => name.as_str(), "password" => password.as_str(),
+        },
+        Command::Msg(sender, target, text) => json::object!{
+            "cmd" => "msg", "sender" => sender.as_str(), "target" => target.as_str(),
+            "text" => text.as_str(),
+        },
+        Command::Join(target) => json::object!{ "cmd" => "join", "target" => target.as_str() },
+        Command::Leave(target) => json::object!{ "cmd" => "leave", "target" => target.as_str() },
+        Command::ListUsr(group, operation, users) => json::object!{
+            "cmd" => "listusr",
+            "group" => group.as_str(),
+            "operation" => match operation {
+                ListUsrOperation::Add => "add",
+                ListUsrOperation::Remove => "remove",
+            },
+            "users" => users.as_str(),
+        },
+        Command::Err(text) => json::object!{ "cmd" => "err", "text" => text.as_str() },
+        Command::Ok => json::object!{ "cmd" => "ok" },
+    }
+}
+
+/// Parses a command sent by a WebSocket client in JSON mode, see `command_to_json`
+pub fn command_from_json(value: &JsonValue) -> Result<Command, io::Error> {
+    let field = |name: &str| -> Result<String, io::Error> {
+        value[name].as_str().map(|s| s.to_string()).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("missing field '{}'", name))
+        })
+    };
+
+    match value["cmd"].as_str().unwrap_or("") {
+        "usr" => Ok(Command::Usr(field("name")?, field("password")?)),
+        "msg" => Ok(Command::Msg(field("sender")?, field("target")?, field("text")?)),
+        "join" => Ok(Command::Join(field("target")?)),
+        "leave" => Ok(Command::Leave(field("target")?)),
+        "listusr" => {
+            let operation = match value["operation"].as_str().unwrap_or("add") {
+                "add" => ListUsrOperation::Add,
+                "remove" => ListUsrOperation::Remove,
+                op => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                format!("unknown operation '{}'", op))),
+            };
+            let users = value["users"].as_str().unwrap_or("").to_string();
+            Ok(Command::ListUsr(field("group")?, operation, users))
+        },
+        "err" => Ok(Command::Err(field("text")?)),
+        "ok" => Ok(Command::Ok),
+        cmd => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown command '{}'", cmd))),
+    }
+}